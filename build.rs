@@ -0,0 +1,67 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// With the `vendored-sql` feature, bundles the SQL scripts stored in
+/// `vendor/sql/<schema sequence>/` into the binary.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_VENDORED_SQL").is_none() {
+        return;
+    }
+
+    let vendor_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("vendor/sql");
+    println!("cargo:rerun-if-changed={}", vendor_dir.display());
+
+    let mut sequences = String::new();
+    for entry in fs::read_dir(&vendor_dir).into_iter().flatten().flatten() {
+        let Some(schema_sequence) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<i32>().ok())
+        else {
+            continue;
+        };
+
+        let mut scripts = Vec::new();
+        collect_scripts(&entry.path(), &mut scripts);
+        scripts.sort();
+
+        let scripts: String = scripts
+            .iter()
+            .map(|path| {
+                let relative = path.strip_prefix(entry.path()).unwrap();
+                let relative: Vec<_> = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect();
+                format!(
+                    "({:?}, include_str!({:?})),",
+                    relative.join("/"),
+                    path.display().to_string()
+                )
+            })
+            .collect();
+        sequences.push_str(&format!("({schema_sequence}, &[{scripts}]),"));
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("vendored_sql.rs");
+    fs::write(
+        out,
+        format!("const VENDORED_SQL: &[(i32, &[(&str, &str)])] = &[{sequences}];\n"),
+    )
+    .unwrap();
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|ext| ext == "sql") {
+            println!("cargo:rerun-if-changed={}", path.display());
+            scripts.push(path);
+        }
+    }
+}
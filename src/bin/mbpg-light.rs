@@ -1,21 +1,78 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    num::ParseIntError,
+    path::PathBuf,
+    str::FromStr,
+};
+
 use clap::Parser;
 use color_eyre::{Result, config::HookBuilder};
-use musicbrainz_light::{MbLight, settings::Settings};
+use indicatif::HumanBytes;
+use musicbrainz_light::{
+    MbLight,
+    packet_source::DirectoryPacketSource,
+    settings::{MbLightSettingsExt, Settings},
+};
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Parser)]
 pub enum Cli {
     /// Initialize the database
-    Init,
+    Init {
+        /// Import the `mbdump*.tar.bz2` archives of a local directory instead of downloading them
+        #[arg(long)]
+        dump_dir: Option<PathBuf>,
+        /// Import this full export, like `20261014-001803`, instead of the latest one
+        #[arg(long, conflicts_with = "dump_dir")]
+        dump_version: Option<String>,
+    },
+    /// List the full exports available for `init --dump-version`
+    ListDumps,
     /// Sync the database with the latest MusicBrainz data
     Sync {
         /// Wait for the next replication packet infinitely
         #[arg(long, short)]
         r#loop: bool,
+        /// Print the SQL statements of the next replication packet without applying it
+        #[arg(long, conflicts_with = "loop")]
+        dry_run: bool,
+        /// Write the dry run statements to a file instead of stdout
+        #[arg(long, short, requires = "dry_run")]
+        output: Option<PathBuf>,
+        /// Read replication packets from a local directory instead of downloading them
+        #[arg(long)]
+        from_dir: Option<PathBuf>,
+    },
+    /// Download replication packets ahead into the packet cache
+    Fetch {
+        /// First replication packet to fetch, defaults to the next one to apply
+        #[arg(long)]
+        from: Option<i32>,
+        /// Last replication packet to fetch, a sequence number or `latest`
+        #[arg(long, default_value = "latest")]
+        until: Until,
     },
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Until {
+    Latest,
+    Sequence(i32),
+}
+
+impl FromStr for Until {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(Until::Latest),
+            sequence => sequence.parse().map(Until::Sequence),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     HookBuilder::default()
@@ -47,10 +104,75 @@ async fn main() -> Result<()> {
 
     let mut mblight = MbLight::try_new(config, db_url).await?;
 
+    if let Cli::Sync {
+        from_dir: Some(dir),
+        ..
+    } = &cli
+    {
+        let source = DirectoryPacketSource::new(dir).with_verifier(mblight.verifier.clone());
+        mblight = mblight.with_packet_source(source);
+    }
+
     match cli {
-        Cli::Init => mblight.init().await?,
-        Cli::Sync { r#loop } => mblight.sync(r#loop).await?,
+        Cli::Init {
+            dump_dir: Some(dump_dir),
+            ..
+        } => mblight.init_from_dir(&dump_dir).await?,
+        Cli::Init {
+            dump_version: Some(dump_version),
+            ..
+        } => mblight.init_version(&dump_version).await?,
+        Cli::Init { .. } => mblight.init().await?,
+        Cli::ListDumps => {
+            for dump in mblight.list_dumps().await? {
+                println!(
+                    "{:<20}{:<22}{:>12}",
+                    dump.version,
+                    dump.modified.as_deref().unwrap_or("-"),
+                    HumanBytes(dump.size).to_string()
+                );
+            }
+        }
+        Cli::Sync {
+            dry_run: true,
+            output,
+            ..
+        } => {
+            let mut output: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            mblight.dry_run(&mut output).await?;
+        }
+        Cli::Sync { r#loop, .. } => {
+            let _server = serve_http(&mblight).await?;
+            mblight.sync(r#loop).await?
+        }
+        Cli::Fetch { from, until } => {
+            let until = match until {
+                Until::Latest => None,
+                Until::Sequence(sequence) => Some(sequence),
+            };
+            mblight.fetch_packets(from, until).await?;
+        }
     }
 
     Ok(())
 }
+
+/// Serves the status and metrics endpoints while syncing, when `http.listen` is set.
+#[cfg(feature = "http")]
+async fn serve_http(mblight: &MbLight<Settings>) -> Result<Option<tokio::task::JoinHandle<()>>> {
+    match mblight.config.http_listen() {
+        Some(addr) => Ok(Some(mblight.serve_http(addr).await?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+async fn serve_http(mblight: &MbLight<Settings>) -> Result<Option<()>> {
+    if mblight.config.http_listen().is_some() {
+        tracing::warn!("'http.listen' is ignored, mbpg-light was built without the 'http' feature");
+    }
+    Ok(None)
+}
@@ -0,0 +1,134 @@
+//! Parsing of the HTML directory listings served for the full export.
+
+/// A file or directory of a listing, directories end with `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub modified: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl IndexEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// Parses an nginx or Apache autoindex page, where each link is followed on the same line
+/// by its modification date and its size (`-` for directories).
+pub fn parse_index(html: &str) -> Vec<IndexEntry> {
+    let mut entries = Vec::new();
+    for line in html.lines() {
+        let mut rest = line;
+        while let Some(start) = rest.find("<a href=\"") {
+            rest = &rest[start + 9..];
+            let Some(end) = rest.find('"') else {
+                break;
+            };
+            let name = &rest[..end];
+            let details = rest
+                .find("</a>")
+                .map(|close| &rest[close + 4..])
+                .unwrap_or_default();
+            let details = details.split("<a ").next().unwrap_or_default();
+
+            if name.starts_with(['?', '/']) || name.starts_with("..") {
+                continue;
+            }
+
+            let text = strip_tags(details);
+            let mut fields: Vec<&str> = text.split_whitespace().collect();
+            let size = fields.pop().and_then(parse_size);
+            let modified = (!fields.is_empty()).then(|| fields.join(" "));
+            entries.push(IndexEntry {
+                name: name.to_string(),
+                modified,
+                size,
+            });
+        }
+    }
+
+    entries
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Sizes are exact byte counts with nginx, and rounded like `6.0G` with Apache.
+fn parse_size(size: &str) -> Option<u64> {
+    if let Ok(bytes) = size.parse() {
+        return Some(bytes);
+    }
+
+    let unit: u64 = match size.chars().last()? {
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        'T' => 1 << 40,
+        _ => return None,
+    };
+    let value: f64 = size[..size.len() - 1].parse().ok()?;
+    Some((value * unit as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nginx_index() {
+        let html = r#"<html>
+<head><title>Index of /pub/musicbrainz/data/fullexport/</title></head>
+<body>
+<h1>Index of /pub/musicbrainz/data/fullexport/</h1><hr><pre><a href="../">../</a>
+<a href="20261011-001756/">20261011-001756/</a>                                   11-Oct-2026 03:41                   -
+<a href="20261014-001803/">20261014-001803/</a>                                   14-Oct-2026 03:47                   -
+<a href="LATEST">LATEST</a>                                             14-Oct-2026 03:47                  16
+</pre><hr></body>
+</html>"#;
+
+        let entries = parse_index(html);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[1],
+            IndexEntry {
+                name: "20261014-001803/".to_string(),
+                modified: Some("14-Oct-2026 03:47".to_string()),
+                size: None,
+            }
+        );
+        assert!(entries[1].is_dir());
+        assert_eq!(entries[2].size, Some(16));
+    }
+
+    #[test]
+    fn test_parse_apache_index() {
+        let html = r#"<table>
+<tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+<tr><td><a href="/pub/musicbrainz/data/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td></tr>
+<tr><td><a href="mbdump.tar.bz2">mbdump.tar.bz2</a></td><td align="right">2026-10-14 02:44  </td><td align="right">6.0G</td></tr>
+<tr><td><a href="mbdump-derived.tar.bz2">mbdump-derived.tar.bz2</a></td><td align="right">2026-10-14 02:51  </td><td align="right">512M</td></tr>
+</table>"#;
+
+        let entries = parse_index(html);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "mbdump.tar.bz2");
+        assert_eq!(entries[0].modified.as_deref(), Some("2026-10-14 02:44"));
+        assert_eq!(entries[0].size, Some(6 << 30));
+        assert_eq!(entries[1].size, Some(512 << 20));
+    }
+}
@@ -1,133 +1,454 @@
+use crate::download::body_reader::BodyReader;
 use crate::error::MbLightResult;
+use crate::musicbrainz_db::dump_metadata::DumpMetadata;
+use crate::musicbrainz_db::init::{EXTENSION_SCRIPTS, SETUP_SCRIPTS, TABLE_SCRIPTS};
+use crate::musicbrainz_db::replication::replication_control::SchemaSequence;
+use crate::musicbrainz_db::replication::schema_change::{MIRROR_SCRIPTS, SCHEMA_CHANGE_DIR};
 use crate::progress::get_progress_bar;
 use crate::settings::MbLightSettingsExt;
 use crate::{MbLight, MbLightError};
-use futures_util::future::join_all;
-use indicatif::MultiProgress;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use reqwest::header::USER_AGENT;
 use std::fs;
-use std::path::PathBuf;
-use tempfile::env::temp_dir;
-use tracing::error;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
+use tokio::sync::mpsc;
+use tracing::info;
+
+const OWNER: &str = "metabrainz";
+const REPO: &str = "musicbrainz-server";
+const GITHUB_API: &str = "https://api.github.com";
+const PRODUCTION_BRANCH: &str = "production";
+/// Written once every script of a schema sequence is stored, holds the git ref they come from.
+const COMPLETE_MARKER: &str = ".complete";
+/// Git ref in the marker of scripts bundled by the `vendored-sql` feature.
+#[cfg_attr(not(feature = "vendored-sql"), allow(dead_code))]
+const VENDORED_REF: &str = "vendored";
+
+/// What the `admin/sql` scripts of a schema sequence are needed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SqlPurpose {
+    /// Creating the schema, by `init`.
+    Init,
+    /// Upgrading a mirror to the schema sequence.
+    SchemaChange,
+}
+
+impl SqlPurpose {
+    /// First script needed for this purpose that `has` lacks, relative to `admin/sql`.
+    pub(crate) fn missing_script(
+        self,
+        schema_sequence: i32,
+        has: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        match self {
+            SqlPurpose::Init => EXTENSION_SCRIPTS
+                .iter()
+                .copied()
+                .chain(
+                    TABLE_SCRIPTS
+                        .iter()
+                        .chain(SETUP_SCRIPTS)
+                        .map(|(_, script)| *script),
+                )
+                .find(|script| !has(script))
+                .map(str::to_string),
+            SqlPurpose::SchemaChange => {
+                let script =
+                    |name: &str| format!("{SCHEMA_CHANGE_DIR}/{schema_sequence}.{name}.sql");
+                // Each mirror script is optional, but a schema change has at least one
+                let any = MIRROR_SCRIPTS
+                    .iter()
+                    .flat_map(|names| names.iter())
+                    .any(|name| has(&script(name)));
+                (!any).then(|| script("all"))
+            }
+        }
+    }
+}
+
+/// Whether the scripts cached in `local_dir` can be used: they were all stored, at
+/// `wanted_ref` when set, and cover `purpose`. Unusable scripts are removed.
+fn use_cached(
+    local_dir: &Path,
+    schema_sequence: SchemaSequence,
+    wanted_ref: Option<&str>,
+    purpose: SqlPurpose,
+) -> MbLightResult<bool> {
+    let Ok(git_ref) = fs::read_to_string(local_dir.join(COMPLETE_MARKER)) else {
+        return Ok(false);
+    };
+    let git_ref = git_ref.trim();
+
+    let stale = if wanted_ref.is_some_and(|wanted| wanted != git_ref) {
+        Some(format!("are at {git_ref}"))
+    } else {
+        purpose
+            .missing_script(schema_sequence.0, |script| local_dir.join(script).is_file())
+            .map(|missing| format!("lack {missing}"))
+    };
+    match stale {
+        None => {
+            info!("Using SQL scripts of schema {schema_sequence} from {git_ref}");
+            Ok(true)
+        }
+        Some(reason) => {
+            info!("Cached SQL scripts of schema {schema_sequence} {reason}, fetching them again");
+            fs::remove_dir_all(local_dir)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Unpacks the `admin/sql` files of a repository `.tar.gz` into `dest`, returns how many
+/// were extracted.
+fn extract_admin_sql(input: impl Read, dest: &Path) -> MbLightResult<usize> {
+    let mut archive = Archive::new(GzDecoder::new(input));
+    let mut extracted = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        // Entries are nested in a `<owner>-<repo>-<sha>/` directory
+        let path: PathBuf = path.components().skip(1).collect();
+        let Ok(script) = path.strip_prefix("admin/sql") else {
+            // Entries are sorted, nothing is left to extract past `admin/sql`
+            if extracted > 0 {
+                break;
+            }
+            continue;
+        };
+
+        let is_safe = script
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !entry.header().entry_type().is_file() || !is_safe {
+            continue;
+        }
+
+        let target = dest.join(script);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&target)?;
+        extracted += 1;
+    }
+
+    Ok(extracted)
+}
 
 impl<S: MbLightSettingsExt> MbLight<S> {
-    pub async fn download_musicbrainz_sql(&self) -> MbLightResult<PathBuf> {
-        let owner = "metabrainz";
-        let repo = "musicbrainz-server";
-        let path = "admin/sql";
-        let local_dir = temp_dir();
-        let local_dir = local_dir.join("musicbrainz-sql");
-
-        let mp = MultiProgress::new();
-        self.download_dir(
-            owner.into(),
-            repo.into(),
-            path.into(),
-            local_dir.clone(),
-            mp.clone(),
+    /// Local copy of the `admin/sql` scripts matching the dump's schema sequence.
+    pub async fn download_musicbrainz_sql(
+        &self,
+        metadata: &DumpMetadata,
+    ) -> MbLightResult<PathBuf> {
+        self.admin_sql(
+            metadata.schema_sequence,
+            Some(metadata.timestamp),
+            SqlPurpose::Init,
         )
-        .await?;
+        .await
+    }
+
+    /// Local copy of the `admin/sql` scripts of `schema_sequence`.
+    ///
+    /// The [`MbLightSettingsExt::musicbrainz_server_checkout`] is used when set. Otherwise
+    /// scripts are looked up in [`MbLightSettingsExt::sql_cache_dir`], then among the ones
+    /// bundled by the `vendored-sql` feature, and downloaded as a last resort: at the
+    /// [`MbLightSettingsExt::sql_ref`] or the production commit deployed at `exported_at`,
+    /// or at the head of `production` for a schema that has no dump yet. Cached or bundled
+    /// scripts are only used when they are at the wanted ref and cover `purpose`.
+    pub(crate) async fn admin_sql(
+        &self,
+        schema_sequence: SchemaSequence,
+        exported_at: Option<DateTime<Utc>>,
+        purpose: SqlPurpose,
+    ) -> MbLightResult<PathBuf> {
+        if let Some(checkout) = self.config.musicbrainz_server_checkout() {
+            let local_dir = checkout.join("admin/sql");
+            if !local_dir.is_dir() {
+                return Err(MbLightError::MissingSqlScripts(
+                    checkout.display().to_string(),
+                ));
+            }
+            info!("Using SQL scripts of {}", checkout.display());
+            return Ok(local_dir);
+        }
+
+        let local_dir = self
+            .config
+            .sql_cache_dir()
+            .join(schema_sequence.to_string());
+        let marker = local_dir.join(COMPLETE_MARKER);
+        // The configured ref only applies to the scripts matching a dump
+        let wanted_ref = exported_at.and(self.config.sql_ref());
+        if use_cached(&local_dir, schema_sequence, wanted_ref, purpose)? {
+            return Ok(local_dir);
+        }
+        fs::create_dir_all(&local_dir)?;
+
+        #[cfg(feature = "vendored-sql")]
+        if let Some(scripts) = wanted_ref
+            .is_none()
+            .then(|| super::vendored_sql::scripts(schema_sequence.0, purpose))
+            .flatten()
+        {
+            info!("Using bundled SQL scripts of schema {schema_sequence}");
+            for (script, content) in scripts {
+                let path = local_dir.join(script);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, content)?;
+            }
+            fs::write(&marker, VENDORED_REF)?;
+            return Ok(local_dir);
+        }
 
-        mp.clear()?;
+        let git_ref = match (exported_at, self.config.sql_ref()) {
+            (Some(_), Some(git_ref)) => git_ref.to_string(),
+            (Some(exported_at), None) => self.production_commit(exported_at).await?,
+            (None, _) => PRODUCTION_BRANCH.to_string(),
+        };
+        info!("Downloading SQL scripts of schema {schema_sequence} at {git_ref}");
+
+        self.download_admin_sql(&git_ref, &local_dir).await?;
+        // An incomplete set is not marked, so the next run fetches it again
+        if let Some(missing) =
+            purpose.missing_script(schema_sequence.0, |script| local_dir.join(script).is_file())
+        {
+            return Err(MbLightError::MissingSqlScripts(format!(
+                "{REPO}@{git_ref}/admin/sql/{missing}"
+            )));
+        }
+        fs::write(&marker, &git_ref)?;
         Ok(local_dir)
     }
 
-    pub async fn download_schema_update(&self, target_sequence: i32) -> MbLightResult<PathBuf> {
-        let owner = "metabrainz";
-        let repo = "musicbrainz-server";
-        let path = format!("admin/sql/update/schema-change/{}.all.sql", target_sequence);
-        let local_dir = temp_dir();
-        let local_dir = local_dir.join("musicbrainz-sql");
-
-        let mp = MultiProgress::new();
-        let path_clone = PathBuf::from(&path);
-        self.download_dir(
-            owner.into(),
-            repo.into(),
-            path,
-            local_dir.clone(),
-            mp.clone(),
-        )
-        .await?;
+    /// Extracts `admin/sql` from the repository tarball at `git_ref` into `local_dir`, with a
+    /// single request.
+    async fn download_admin_sql(&self, git_ref: &str, local_dir: &Path) -> MbLightResult<()> {
+        let mut request = self
+            .http_client
+            .get(format!(
+                "{GITHUB_API}/repos/{OWNER}/{REPO}/tarball/{git_ref}"
+            ))
+            .header(USER_AGENT, "mbpg-light");
+        if let Some(token) = self.config.github_token() {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?.error_for_status()?;
 
-        mp.clear()?;
-        Ok(path_clone)
+        let pb = get_progress_bar(response.content_length().unwrap_or(0))?;
+        pb.set_message(format!("{REPO} {git_ref}"));
+        let mut body = response.bytes_stream();
+        let (body_tx, body_rx) = mpsc::channel(16);
+        let download = async move {
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                pb.inc(chunk.len() as u64);
+                // The extraction stops once past `admin/sql`
+                if body_tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+            pb.finish_and_clear();
+            Ok(())
+        };
+
+        let dest = local_dir.to_path_buf();
+        let extract = async {
+            tokio::task::spawn_blocking(move || extract_admin_sql(BodyReader::new(body_rx), &dest))
+                .await
+                .map_err(io::Error::other)?
+        };
+
+        let ((), extracted) = tokio::try_join!(download, extract)?;
+        if extracted == 0 {
+            return Err(MbLightError::MissingSqlScripts(format!("{REPO}@{git_ref}")));
+        }
+
+        info!("Extracted {extracted} SQL scripts of {REPO}@{git_ref}");
+        Ok(())
     }
 
-    async fn download_dir(
-        &self,
-        owner: String,
-        repo: String,
-        path: String,
-        local_path: PathBuf,
-        mp: MultiProgress,
-    ) -> MbLightResult<()> {
-        fs::create_dir_all(&local_path)?;
-
-        let contents = self
-            .github_client
-            .repos(&owner, &repo)
-            .get_content()
-            .path(path)
+    /// Commit of the `production` branch that was deployed when the dump was exported.
+    async fn production_commit(&self, exported_at: DateTime<Utc>) -> MbLightResult<String> {
+        self.github_client
+            .repos(OWNER, REPO)
+            .list_commits()
+            .branch(PRODUCTION_BRANCH)
+            .until(exported_at)
+            .per_page(1u8)
             .send()
             .await?
-            .items;
-
-        let pb = mp.add(get_progress_bar(contents.len() as u64)?);
-        pb.set_message(format!(
-            "Dir {}",
-            local_path.file_name().unwrap_or_default().to_string_lossy()
-        ));
-
-        let mut files = vec![];
-        for item in contents {
-            let item_path = local_path.join(&item.name);
-            let mp = mp.clone();
-            let pb = pb.clone();
-
-            match item.r#type.as_str() {
-                "dir" => {
-                    let owner = owner.clone();
-                    let repo = repo.clone();
-                    let path = item.path.clone();
-                    let local_path = item_path.clone();
-
-                    Box::pin(self.download_dir(owner, repo, path, local_path, mp)).await?;
-                    pb.inc(1);
-                }
-                "file" => {
-                    if let Some(download_url) = item.download_url {
-                        let fut = async move {
-                            let file_path = item_path.clone();
-                            let bytes = self
-                                .http_client
-                                .get(&download_url)
-                                .send()
-                                .await?
-                                .bytes()
-                                .await?;
-                            tokio::fs::write(&file_path, &bytes).await?;
-
-                            pb.inc(1);
-                            Ok::<(), MbLightError>(())
-                        };
-
-                        files.push(fut);
-                    } else {
-                        pb.inc(1);
-                    }
-                }
-                _ => {}
-            }
+            .items
+            .into_iter()
+            .next()
+            .map(|commit| commit.sha)
+            .ok_or(MbLightError::MissingProductionCommit(exported_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Compression, write::GzEncoder};
+
+    use super::*;
+
+    #[test]
+    fn test_extract_admin_sql() -> MbLightResult<()> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, content) in [
+            ("metabrainz-musicbrainz-server-4f3c2a1/README.md", "readme"),
+            (
+                "metabrainz-musicbrainz-server-4f3c2a1/admin/InitDb.pl",
+                "perl",
+            ),
+            (
+                "metabrainz-musicbrainz-server-4f3c2a1/admin/sql/CreateTables.sql",
+                "tables",
+            ),
+            (
+                "metabrainz-musicbrainz-server-4f3c2a1/admin/sql/caa/CreateTables.sql",
+                "caa",
+            ),
+            (
+                "metabrainz-musicbrainz-server-4f3c2a1/lib/DBDefs.pm",
+                "defs",
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content.as_bytes())?;
         }
+        let tarball = builder.into_inner()?.finish()?;
 
-        let results = join_all(files).await;
-        for r in results {
-            if let Err(e) = r {
-                error!("Error: {}", e);
-            }
+        let dest = tempfile::tempdir()?;
+        assert_eq!(extract_admin_sql(tarball.as_slice(), dest.path())?, 2);
+        assert_eq!(
+            fs::read_to_string(dest.path().join("caa/CreateTables.sql"))?,
+            "caa"
+        );
+        assert!(!dest.path().join("README.md").exists());
+        Ok(())
+    }
+
+    fn write_scripts(dir: &Path, scripts: &[String], git_ref: &str) -> MbLightResult<()> {
+        for script in scripts {
+            let path = dir.join(script);
+            fs::create_dir_all(path.parent().expect("script directory"))?;
+            fs::write(path, "SELECT 1;")?;
         }
-        pb.finish_with_message("Download complete");
+        fs::write(dir.join(COMPLETE_MARKER), git_ref)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_script() {
+        let init: Vec<&str> = EXTENSION_SCRIPTS
+            .iter()
+            .copied()
+            .chain(TABLE_SCRIPTS.iter().map(|(_, script)| *script))
+            .collect();
+        assert_eq!(
+            SqlPurpose::Init.missing_script(30, |script| init.contains(&script)),
+            Some(SETUP_SCRIPTS[0].1.to_string())
+        );
+        assert_eq!(SqlPurpose::Init.missing_script(30, |_| true), None);
+
+        assert_eq!(
+            SqlPurpose::SchemaChange.missing_script(30, |script| init.contains(&script)),
+            Some("updates/schema-change/30.all.sql".to_string())
+        );
+        assert_eq!(
+            SqlPurpose::SchemaChange
+                .missing_script(30, |script| script == "updates/schema-change/30.slave.sql"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_use_cached() -> MbLightResult<()> {
+        let cache = tempfile::tempdir()?;
+        let local_dir = cache.path().join("30");
+        let init: Vec<String> = EXTENSION_SCRIPTS
+            .iter()
+            .copied()
+            .chain(
+                TABLE_SCRIPTS
+                    .iter()
+                    .chain(SETUP_SCRIPTS)
+                    .map(|(_, script)| *script),
+            )
+            .map(str::to_string)
+            .collect();
+
+        // Nothing stored, or not marked complete
+        assert!(!use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            None,
+            SqlPurpose::Init
+        )?);
+        fs::create_dir_all(&local_dir)?;
+        fs::write(local_dir.join("Extensions.sql"), "")?;
+        assert!(!use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            None,
+            SqlPurpose::Init
+        )?);
+
+        write_scripts(&local_dir, &init, "4f3c2a1")?;
+        assert!(use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            None,
+            SqlPurpose::Init
+        )?);
+        assert!(use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            Some("4f3c2a1"),
+            SqlPurpose::Init
+        )?);
+
+        // A complete init set without the schema change is fetched again
+        assert!(!use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            None,
+            SqlPurpose::SchemaChange
+        )?);
+        assert!(!local_dir.exists());
+
+        // So is a set at another ref than the configured one
+        write_scripts(&local_dir, &init, VENDORED_REF)?;
+        assert!(!use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            Some("v-2025-05-20"),
+            SqlPurpose::Init
+        )?);
+        assert!(!local_dir.exists());
 
+        write_scripts(
+            &local_dir,
+            &["updates/schema-change/30.all.sql".to_string()],
+            "production",
+        )?;
+        assert!(use_cached(
+            &local_dir,
+            SchemaSequence(30),
+            None,
+            SqlPurpose::SchemaChange
+        )?);
         Ok(())
     }
 }
@@ -1,2 +1,20 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) mod body_reader;
+pub mod dump_index;
 pub mod github;
 pub mod musicbrainz;
+pub mod retry;
+#[cfg(feature = "vendored-sql")]
+mod vendored_sql;
+
+static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes of dumps and replication packets received since the process started.
+pub fn downloaded_bytes() -> u64 {
+    DOWNLOADED_BYTES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn record_downloaded(bytes: usize) {
+    DOWNLOADED_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
@@ -1,24 +1,46 @@
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::Path,
 };
 
 use crate::{
     MbLight,
+    download::{
+        body_reader::BodyReader,
+        dump_index::{IndexEntry, parse_index},
+        retry::RetryPolicy,
+    },
     error::{MbLightError, MbLightResult},
+    musicbrainz_db::dump_metadata::DumpMetadata,
     progress::get_progress_bar,
     settings::MbLightSettingsExt,
 };
-use futures_util::StreamExt;
-use reqwest::StatusCode;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use indicatif::ProgressBar;
+use reqwest::{
+    StatusCode,
+    header::{CONTENT_RANGE, RANGE},
+};
+use tokio::sync::mpsc;
+use tracing::warn;
 
+/// Default [`MbLightSettingsExt::fullexport_url`].
 pub const MUSICBRAINZ_FTP: &str = "http://ftp.musicbrainz.org/pub/musicbrainz/data/fullexport";
 
+/// A full export directory, `size` is the total of its `mbdump*.tar.bz2` archives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpListing {
+    pub version: String,
+    pub modified: Option<String>,
+    pub size: u64,
+}
+
 impl<S: MbLightSettingsExt> MbLight<S> {
     pub async fn get_latest(&self) -> MbLightResult<String> {
         Ok(self
             .http_client
-            .get(format!("{}/LATEST", MUSICBRAINZ_FTP))
+            .get(self.fullexport_file("LATEST"))
             .send()
             .await?
             .text()
@@ -27,46 +49,409 @@ impl<S: MbLightSettingsExt> MbLight<S> {
             .to_string())
     }
 
-    pub async fn download_with_progress(
-        &self,
-        url: &str,
-        tmpfile: &mut File,
-    ) -> Result<(), MbLightError> {
-        let response = self.http_client.get(url).send().await?;
+    /// Full exports available on the server, oldest first.
+    pub async fn list_dumps(&self) -> MbLightResult<Vec<DumpListing>> {
+        let index = self.get_index(&self.fullexport_file("")).await?;
+        let versions = index
+            .iter()
+            .filter(|entry| entry.is_dir() && entry.name.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|entry| entry.name.trim_end_matches('/').to_string());
+
+        let mut dumps: Vec<DumpListing> = stream::iter(versions)
+            .map(|version| async move {
+                let files = self.get_index(&self.fullexport_file(&version)).await?;
+                let archives = files.iter().filter(|file| {
+                    file.name.starts_with("mbdump") && file.name.ends_with(".tar.bz2")
+                });
+                Ok::<_, MbLightError>(DumpListing {
+                    modified: files
+                        .iter()
+                        .find(|file| file.name == "mbdump.tar.bz2")
+                        .and_then(|file| file.modified.clone()),
+                    size: archives.filter_map(|file| file.size).sum(),
+                    version,
+                })
+            })
+            .buffered(4)
+            .try_collect()
+            .await?;
 
+        dumps.sort_by(|a, b| a.version.cmp(&b.version));
+        Ok(dumps)
+    }
+
+    /// Reads the metadata entries at the start of the published `mbdump.tar.bz2`, the rest
+    /// of the archive is not downloaded.
+    pub async fn fetch_dump_metadata(&self, dump_version: &str) -> MbLightResult<DumpMetadata> {
+        let url = self.fullexport_file(&format!("{dump_version}/mbdump.tar.bz2"));
+        let response = self.http_client.get(url).send().await?;
         if response.status() == StatusCode::NOT_FOUND {
-            return Err(MbLightError::NotFound);
+            return Err(MbLightError::UnknownDumpVersion(dump_version.to_string()));
         }
 
-        let total_size = response.content_length().unwrap_or(0);
+        let mut body = response.error_for_status()?.bytes_stream();
+        let (body_tx, body_rx) = mpsc::channel(4);
+        let download = async move {
+            while let Some(chunk) = body.next().await {
+                // The reader is gone once the metadata entries are read
+                if body_tx.send(chunk?).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let read = async {
+            tokio::task::spawn_blocking(move || {
+                DumpMetadata::from_reader(BodyReader::new(body_rx), 1)
+            })
+            .await
+            .map_err(io::Error::other)?
+        };
 
-        let pb = get_progress_bar(total_size)?;
-        pb.set_message(format!("Downloading {}", url));
+        let ((), metadata) = tokio::try_join!(download, read)?;
+        Ok(metadata)
+    }
 
-        let mut writer = BufWriter::with_capacity(8 * 1024 * 1024, tmpfile);
-        let mut stream = response.bytes_stream();
-        let mut buffered_progress: u64 = 0;
-        let update_interval: u64 = 256 * 1024;
+    /// URL of `path` in the full export, see [`MbLightSettingsExt::fullexport_url`].
+    pub(crate) fn fullexport_file(&self, path: &str) -> String {
+        format!(
+            "{}/{path}",
+            self.config.fullexport_url().trim_end_matches('/')
+        )
+    }
 
-        while let Some(chunk) = stream.next().await {
-            let data = chunk?;
-            writer.write_all(&data)?;
-            {
-                buffered_progress += data.len() as u64;
-                if buffered_progress >= update_interval {
-                    pb.inc(buffered_progress);
-                    buffered_progress = 0;
-                }
+    async fn get_index(&self, url: &str) -> MbLightResult<Vec<IndexEntry>> {
+        let html = self
+            .http_client
+            .get(format!("{}/", url.trim_end_matches('/')))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(parse_index(&html))
+    }
+
+    pub async fn download_with_progress(&self, url: &str, path: &Path) -> MbLightResult<()> {
+        download_with_progress(&self.http_client, url, path, &self.config.retry_policy()).await
+    }
+}
+
+/// Downloads `url` into `path`, retrying transient failures according to `retry`.
+///
+/// `path` is a partial file: when it already holds data, the download resumes after it
+/// with an HTTP `Range` request, including across process restarts.
+pub async fn download_with_progress(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    retry: &RetryPolicy,
+) -> MbLightResult<()> {
+    let pb = get_progress_bar(0)?;
+    pb.set_message(format!("Downloading {}", url));
+
+    let mut attempt = 0;
+    loop {
+        match download_attempt(client, url, path, &pb).await {
+            Ok(()) => {
+                pb.finish_with_message(format!("Downloaded {}", url));
+                return Ok(());
+            }
+            Err(DownloadError::Retryable(err)) if attempt < retry.max_retries => {
+                let delay = retry.backoff(attempt);
+                attempt += 1;
+                warn!(
+                    "Download of {url} failed: {err}, retrying in {delay:?} ({attempt}/{})",
+                    retry.max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(DownloadError::Retryable(err) | DownloadError::Fatal(err)) => {
+                pb.abandon_with_message(format!("Failed to download {}", url));
+                return Err(err);
             }
         }
+    }
+}
+
+enum DownloadError {
+    /// Network failures, server errors and throttling, worth another attempt.
+    Retryable(MbLightError),
+    Fatal(MbLightError),
+}
 
-        {
-            if buffered_progress > 0 {
-                pb.inc(buffered_progress);
+impl From<std::io::Error> for DownloadError {
+    fn from(err: std::io::Error) -> Self {
+        DownloadError::Fatal(err.into())
+    }
+}
+
+async fn download_attempt(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    pb: &ProgressBar,
+) -> Result<(), DownloadError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut offset = file.metadata()?.len();
+
+    let response = loop {
+        let mut request = client.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| DownloadError::Retryable(e.into()))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => return Err(DownloadError::Fatal(MbLightError::NotFound)),
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                let (_, total) = content_range(&response);
+                // The partial file already holds the whole content
+                if total == Some(offset) {
+                    return Ok(());
+                }
+                let total = total.map_or("unknown".to_string(), |total| total.to_string());
+                warn!(
+                    "Partial download of {url} does not match the remote file ({offset} of \
+                     {total} bytes), starting over"
+                );
             }
+            StatusCode::PARTIAL_CONTENT => {
+                let (start, _) = content_range(&response);
+                if start == Some(offset) {
+                    break response;
+                }
+                warn!("Download of {url} did not resume at {offset}, starting over");
+            }
+            status if status.is_success() => {
+                // Range not supported, start over
+                if offset > 0 {
+                    file.set_len(0)?;
+                    offset = 0;
+                }
+                break response;
+            }
+            status => {
+                let retryable = status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT;
+                let err = match response.error_for_status() {
+                    Err(err) => err.into(),
+                    Ok(_) => MbLightError::UnexpectedStatus(status.as_u16()),
+                };
+                return Err(if retryable {
+                    DownloadError::Retryable(err)
+                } else {
+                    DownloadError::Fatal(err)
+                });
+            }
+        }
+
+        // Not the continuation of the partial file, download it again
+        file.set_len(0)?;
+        offset = 0;
+    };
+
+    pb.set_length(offset + response.content_length().unwrap_or(0));
+    pb.set_position(offset);
 
-            pb.finish_with_message(format!("Downloaded {}", url));
+    let mut writer = BufWriter::with_capacity(8 * 1024 * 1024, file);
+    let mut stream = response.bytes_stream();
+    let mut buffered_progress: u64 = 0;
+    let update_interval: u64 = 256 * 1024;
+
+    while let Some(chunk) = stream.next().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(err) => {
+                // Keep what was received so far for the next attempt
+                writer.flush()?;
+                return Err(DownloadError::Retryable(err.into()));
+            }
+        };
+        writer.write_all(&data)?;
+        super::record_downloaded(data.len());
+        buffered_progress += data.len() as u64;
+        if buffered_progress >= update_interval {
+            pb.inc(buffered_progress);
+            buffered_progress = 0;
         }
+    }
+
+    writer.flush()?;
+    pb.inc(buffered_progress);
+    Ok(())
+}
+
+/// Start and total length of a `Content-Range: bytes <start>-<end>/<total>` header, `None` when
+/// missing or unknown (`*`).
+fn content_range(response: &reqwest::Response) -> (Option<u64>, Option<u64>) {
+    let Some(range) = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+    else {
+        return (None, None);
+    };
+    let (range, total) = range.split_once('/').unwrap_or((range, "*"));
+    let start = range
+        .split_once('-')
+        .and_then(|(start, _)| start.trim().parse().ok());
+    (start, total.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{header, method, path},
+    };
+
+    use super::*;
+
+    const RETRY: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(5),
+    };
+
+    #[tokio::test]
+    async fn test_retry_server_errors() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/packet"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/packet"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes("packet"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let file = tempfile::NamedTempFile::new()?;
+        let url = format!("{}/packet", server.uri());
+        download_with_progress(&reqwest::Client::new(), &url, file.path(), &RETRY).await?;
+
+        assert_eq!(std::fs::read(file.path())?, b"packet");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_partial_file() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/mbdump.tar.bz2"))
+            .and(header("Range", "bytes=6-"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("Content-Range", "bytes 6-10/11")
+                    .set_body_bytes("world"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), "hello ")?;
+        let url = format!("{}/mbdump.tar.bz2", server.uri());
+        download_with_progress(&reqwest::Client::new(), &url, file.path(), &RETRY).await?;
+
+        assert_eq!(std::fs::read(file.path())?, b"hello world");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_mismatched_range() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        // Ignores the requested range
+        Mock::given(path("/shifted"))
+            .and(header("Range", "bytes=6-"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("Content-Range", "bytes 0-10/11")
+                    .set_body_bytes("hello world"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The remote file is shorter than the partial one
+        Mock::given(path("/shorter"))
+            .and(header("Range", "bytes=11-"))
+            .respond_with(ResponseTemplate::new(416).insert_header("Content-Range", "bytes */5"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/complete"))
+            .and(header("Range", "bytes=11-"))
+            .respond_with(ResponseTemplate::new(416).insert_header("Content-Range", "bytes */11"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/shifted"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes("hello world"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/shorter"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), "hello ")?;
+        let url = format!("{}/shifted", server.uri());
+        download_with_progress(&client, &url, file.path(), &RETRY).await?;
+        assert_eq!(std::fs::read(file.path())?, b"hello world");
+
+        let url = format!("{}/complete", server.uri());
+        download_with_progress(&client, &url, file.path(), &RETRY).await?;
+        assert_eq!(std::fs::read(file.path())?, b"hello world");
+
+        let url = format!("{}/shorter", server.uri());
+        download_with_progress(&client, &url, file.path(), &RETRY).await?;
+        assert_eq!(std::fs::read(file.path())?, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        Mock::given(path("/missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/forbidden"))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let file = tempfile::NamedTempFile::new()?;
+        let missing = format!("{}/missing", server.uri());
+        assert!(matches!(
+            download_with_progress(&client, &missing, file.path(), &RETRY).await,
+            Err(MbLightError::NotFound)
+        ));
+        let forbidden = format!("{}/forbidden", server.uri());
+        assert!(matches!(
+            download_with_progress(&client, &forbidden, file.path(), &RETRY).await,
+            Err(MbLightError::Http(_))
+        ));
         Ok(())
     }
 }
@@ -0,0 +1,27 @@
+//! SQL scripts bundled at build time from `vendor/sql/<schema sequence>/`.
+
+use tracing::warn;
+
+use crate::download::github::SqlPurpose;
+
+include!(concat!(env!("OUT_DIR"), "/vendored_sql.rs"));
+
+/// Bundled scripts of `schema_sequence` with their path relative to `admin/sql`, `None`
+/// unless every script needed for `purpose` is there.
+pub fn scripts(
+    schema_sequence: i32,
+    purpose: SqlPurpose,
+) -> Option<&'static [(&'static str, &'static str)]> {
+    let (_, scripts) = VENDORED_SQL
+        .iter()
+        .find(|(sequence, _)| *sequence == schema_sequence)?;
+
+    if let Some(missing) = purpose.missing_script(schema_sequence, |script| {
+        scripts.iter().any(|(path, _)| *path == script)
+    }) {
+        warn!("Bundled SQL scripts of schema {schema_sequence} lack {missing}, ignoring them");
+        return None;
+    }
+
+    Some(scripts)
+}
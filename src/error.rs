@@ -18,8 +18,6 @@ pub enum MbLightError {
     Parse(#[from] serde_json::Error),
     #[error("Parse int error: {0}")]
     ParseInt(#[from] std::num::ParseIntError),
-    #[error("Send error: {0}")]
-    Send(#[from] tokio::sync::mpsc::error::SendError<()>),
     #[error("Replication sequence missmatch, expected {expected} but got {got}")]
     SequenceMissmatch { expected: i32, got: i32 },
     #[error("Replication schema missmatch, expected {expected} but got {got}")]
@@ -36,6 +34,50 @@ pub enum MbLightError {
     MissingPendingData(&'static str),
     #[error("Malformed pending data {0}")]
     MalformedPendingData(&'static str),
+    #[error("Unknown table {0} in pending data")]
+    UnknownTable(String),
+    #[error("Unknown column {0} in pending data")]
+    UnknownColumn(String),
     #[error("No replication sequence in 'replication_control' table")]
     MissingRepplicationSequence,
+    #[error("No schema sequence in 'replication_control' table")]
+    MissingSchemaSequence,
+    #[error(
+        "Dump version missmatch, an unfinished import of {expected} exists but got {got}, drop the 'mblight_meta' schema to start over"
+    )]
+    DumpVersionMissmatch { expected: String, got: String },
+    #[error("No packet cache directory configured, set 'replication.packet_cache'")]
+    MissingPacketCache,
+    #[error("Checksum missmatch for {file}, expected {expected} but got {got}")]
+    ChecksumMissmatch {
+        file: String,
+        expected: String,
+        got: String,
+    },
+    #[error("Invalid signature for {file}: {reason}")]
+    InvalidSignature { file: String, reason: String },
+    #[error("Unexpected HTTP status {0}")]
+    UnexpectedStatus(u16),
+    #[error("Dump archive {0} not found")]
+    MissingDumpArchive(std::path::PathBuf),
+    #[error("No {0} entry in the dump archive")]
+    MissingDumpMetadata(&'static str),
+    #[error("Dump version {0} not found, see the available ones with 'list-dumps'")]
+    UnknownDumpVersion(String),
+    #[error("No musicbrainz-server production commit found before {0}, set 'musicbrainz.sql_ref'")]
+    MissingProductionCommit(chrono::DateTime<chrono::Utc>),
+    #[error("No admin/sql scripts found in {0}")]
+    MissingSqlScripts(String),
+    #[error(
+        "No published checksum for {0}, set 'musicbrainz.skip_checksums' to import it unverified"
+    )]
+    MissingChecksum(String),
+    #[error("Download of {file} interrupted: {source}")]
+    StreamInterrupted {
+        file: String,
+        source: reqwest::Error,
+    },
+    #[cfg(feature = "http")]
+    #[error("Metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
 }
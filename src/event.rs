@@ -0,0 +1,33 @@
+use sqlx::types::chrono::{DateTime, Utc};
+
+use crate::musicbrainz_db::replication::{
+    changes::AppliedChanges,
+    replication_control::{ReplicationSequence, SchemaSequence},
+};
+
+/// Progress of [`crate::MbLight`], received through [`crate::MbLight::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum MbLightEvent {
+    /// A replication packet was fetched and is about to be applied.
+    PacketStarted {
+        sequence: ReplicationSequence,
+        timestamp: DateTime<Utc>,
+    },
+    /// The schema was upgraded before applying a packet.
+    SchemaUpgraded { schema_sequence: SchemaSequence },
+    /// A replication packet was applied and recorded in `replication_control`. The timestamp
+    /// is unknown for a packet resumed from `dbmirror2.pending_data`.
+    PacketApplied {
+        sequence: ReplicationSequence,
+        timestamp: Option<DateTime<Utc>>,
+        changes: AppliedChanges,
+    },
+    /// Replication reached the latest published packet.
+    CaughtUp { sequence: ReplicationSequence },
+    /// A dump table was copied by `init`.
+    TableIngested { schema: String, table: String },
+    /// A post-sync step failed, replication carries on.
+    PostSyncFailed { step: String, error: String },
+    /// Replication stopped on an error.
+    Error(String),
+}
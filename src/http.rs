@@ -0,0 +1,422 @@
+//! Embedded HTTP server for long-running `sync --loop`: `/healthz`, `/status` and Prometheus
+//! `/metrics`, fed by the [`MbLightEvent`]s of [`MbLight::subscribe`].
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing::get,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+use serde::Serialize;
+use sqlx::{
+    PgPool,
+    types::chrono::{DateTime, Utc},
+};
+use tokio::{net::TcpListener, sync::broadcast, task::JoinHandle};
+use tracing::{error, info};
+
+use crate::{
+    MbLight, MbLightEvent, ReplicationSequence, SchemaSequence, download::downloaded_bytes,
+    error::MbLightResult, musicbrainz_db::replication::replication_control::ReplicationControl,
+    settings::MbLightSettingsExt,
+};
+
+/// Packet apply durations, in seconds: packets take from seconds to tens of minutes.
+const APPLY_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// Bytes downloaded by the whole process, outside of the events, read from
+/// [`downloaded_bytes`] when the metrics are gathered.
+#[derive(Clone)]
+struct DownloadBytes {
+    opts: Opts,
+    desc: IntCounter,
+}
+
+impl DownloadBytes {
+    fn new() -> MbLightResult<Self> {
+        let opts = Opts::new(
+            "download_bytes_total",
+            "Bytes of dumps and replication packets downloaded",
+        );
+        let desc = IntCounter::with_opts(opts.clone())?;
+        Ok(Self { opts, desc })
+    }
+}
+
+impl Collector for DownloadBytes {
+    fn desc(&self) -> Vec<&Desc> {
+        self.desc.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match IntCounter::with_opts(self.opts.clone()) {
+            Ok(counter) => {
+                counter.inc_by(downloaded_bytes());
+                counter.collect()
+            }
+            Err(err) => {
+                error!("Failed to collect the downloaded bytes: {err}");
+                vec![]
+            }
+        }
+    }
+}
+
+/// Prometheus metrics of replication.
+pub struct Metrics {
+    registry: Registry,
+    packet_apply_seconds: Histogram,
+    rows_applied: IntCounterVec,
+    errors: IntCounterVec,
+    replication_sequence: IntGauge,
+    replication_lag: IntGauge,
+    // Start of the packet being applied
+    packet_started: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> MbLightResult<Self> {
+        let registry = Registry::new_custom(Some("mbpg_light".to_string()), None)?;
+        let packet_apply_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "packet_apply_seconds",
+                "Time spent applying a replication packet",
+            )
+            .buckets(APPLY_BUCKETS.to_vec()),
+        )?;
+        let rows_applied = IntCounterVec::new(
+            Opts::new("rows_applied_total", "Replicated rows applied"),
+            &["table", "op"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Replication and post-sync errors"),
+            &["kind"],
+        )?;
+        let replication_sequence = IntGauge::new(
+            "replication_sequence",
+            "Sequence of the last applied replication packet",
+        )?;
+        let replication_lag = IntGauge::new(
+            "replication_lag_seconds",
+            "Seconds since the export of the last applied replication packet",
+        )?;
+
+        registry.register(Box::new(packet_apply_seconds.clone()))?;
+        registry.register(Box::new(rows_applied.clone()))?;
+        registry.register(Box::new(DownloadBytes::new()?))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(replication_sequence.clone()))?;
+        registry.register(Box::new(replication_lag.clone()))?;
+
+        Ok(Self {
+            registry,
+            packet_apply_seconds,
+            rows_applied,
+            errors,
+            replication_sequence,
+            replication_lag,
+            packet_started: Mutex::new(None),
+        })
+    }
+
+    pub fn observe(&self, event: &MbLightEvent) {
+        let mut packet_started = self.packet_started.lock().expect("metrics lock poisoned");
+        match event {
+            MbLightEvent::PacketStarted { .. } => *packet_started = Some(Instant::now()),
+            MbLightEvent::PacketApplied {
+                sequence, changes, ..
+            } => {
+                // Resumed packets have no start
+                if let Some(started) = packet_started.take() {
+                    self.packet_apply_seconds
+                        .observe(started.elapsed().as_secs_f64());
+                }
+                for (table, rows) in changes {
+                    for (op, count) in [
+                        ("insert", rows.changes.inserts),
+                        ("update", rows.changes.updates),
+                        ("delete", rows.changes.deletes),
+                    ] {
+                        self.rows_applied
+                            .with_label_values(&[table.as_str(), op])
+                            .inc_by(count);
+                    }
+                }
+                self.replication_sequence.set(sequence.0.into());
+            }
+            MbLightEvent::CaughtUp { sequence } => self.replication_sequence.set(sequence.0.into()),
+            MbLightEvent::PostSyncFailed { .. } => {
+                self.errors.with_label_values(&["post_sync"]).inc()
+            }
+            MbLightEvent::Error(_) => {
+                *packet_started = None;
+                self.errors.with_label_values(&["replication"]).inc()
+            }
+            MbLightEvent::SchemaUpgraded { .. } | MbLightEvent::TableIngested { .. } => {}
+        }
+    }
+
+    /// Updates the lag from `replication_control`, read when the metrics are scraped.
+    pub fn observe_control(&self, control: &ReplicationControl) {
+        if let Some(lag) = lag_seconds(control) {
+            self.replication_lag.set(lag);
+        }
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> MbLightResult<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    async fn collect(&self, mut events: broadcast::Receiver<MbLightEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => self.observe(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    error!("Metrics missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// Body of `/status`.
+#[derive(Debug, Serialize)]
+struct Status {
+    schema_sequence: Option<SchemaSequence>,
+    replication_sequence: Option<ReplicationSequence>,
+    last_replication_date: Option<DateTime<Utc>>,
+    /// Seconds since `last_replication_date`.
+    lag_seconds: Option<i64>,
+}
+
+/// Seconds since the last applied packet was exported.
+fn lag_seconds(control: &ReplicationControl) -> Option<i64> {
+    control
+        .last_replication_date
+        .map(|date| (Utc::now() - date).num_seconds())
+}
+
+impl From<ReplicationControl> for Status {
+    fn from(control: ReplicationControl) -> Self {
+        Self {
+            schema_sequence: control.current_schema_sequence,
+            replication_sequence: control.current_replication_sequence,
+            last_replication_date: control.last_replication_date,
+            lag_seconds: lag_seconds(&control),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    db: PgPool,
+    metrics: Arc<Metrics>,
+}
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Serves `/healthz`, `/status` and `/metrics` on `addr`, like `127.0.0.1:9187`, until the
+    /// returned task is aborted. Metrics are collected from the events emitted from now on.
+    pub async fn serve_http(&self, addr: &str) -> MbLightResult<JoinHandle<()>> {
+        let metrics = Arc::new(Metrics::new()?);
+        let events = self.subscribe();
+        let listener = TcpListener::bind(addr).await?;
+        info!(
+            "Serving status and metrics on http://{}",
+            listener.local_addr()?
+        );
+
+        let app = router(AppState {
+            db: self.db.clone(),
+            metrics: metrics.clone(),
+        });
+        Ok(tokio::spawn(async move {
+            tokio::join!(metrics.collect(events), async {
+                if let Err(err) = axum::serve(listener, app).await {
+                    error!("HTTP server stopped: {err}");
+                }
+            });
+        }))
+    }
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+/// Healthy as long as the database answers.
+async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    match sqlx::query("SELECT 1").execute(&state.db).await {
+        Ok(_) => (StatusCode::OK, "ok".to_string()),
+        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+    }
+}
+
+async fn status(State(state): State<AppState>) -> Result<Json<Status>, (StatusCode, String)> {
+    ReplicationControl::get(&state.db)
+        .await
+        .map(|control| Json(control.into()))
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err.to_string()))
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    match ReplicationControl::get(&state.db).await {
+        Ok(control) => state.metrics.observe_control(&control),
+        Err(err) => error!("Failed to read the replication lag: {err}"),
+    }
+    match state.metrics.render() {
+        Ok(body) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            body,
+        )
+            .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppliedChanges, TableChanges, TableRows};
+
+    #[test]
+    fn test_metrics_from_events() -> MbLightResult<()> {
+        let metrics = Metrics::new()?;
+        let mut changes = AppliedChanges::new();
+        changes.insert(
+            "musicbrainz.artist".to_string(),
+            TableRows {
+                changes: TableChanges {
+                    inserts: 2,
+                    updates: 1,
+                    deletes: 0,
+                },
+                keys: vec![],
+            },
+        );
+
+        metrics.observe(&MbLightEvent::PacketStarted {
+            sequence: ReplicationSequence(101),
+            timestamp: DateTime::UNIX_EPOCH,
+        });
+        metrics.observe(&MbLightEvent::PacketApplied {
+            sequence: ReplicationSequence(101),
+            timestamp: Some(DateTime::UNIX_EPOCH),
+            changes,
+        });
+        metrics.observe(&MbLightEvent::PostSyncFailed {
+            step: "refresh of artist_stats".to_string(),
+            error: "relation does not exist".to_string(),
+        });
+        metrics.observe_control(&ReplicationControl {
+            current_schema_sequence: Some(SchemaSequence(30)),
+            current_replication_sequence: Some(ReplicationSequence(101)),
+            last_replication_date: Some(Utc::now() - chrono::Duration::hours(2)),
+        });
+
+        let body = metrics.render()?;
+        for line in [
+            "mbpg_light_packet_apply_seconds_count 1",
+            r#"mbpg_light_rows_applied_total{op="insert",table="musicbrainz.artist"} 2"#,
+            r#"mbpg_light_rows_applied_total{op="update",table="musicbrainz.artist"} 1"#,
+            r#"mbpg_light_errors_total{kind="post_sync"} 1"#,
+            "mbpg_light_replication_sequence 101",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{line} missing in:\n{body}"
+            );
+        }
+        let downloaded: u64 = body
+            .lines()
+            .find_map(|line| line.strip_prefix("mbpg_light_download_bytes_total "))
+            .expect("download counter")
+            .parse()
+            .expect("downloaded bytes");
+        assert!(downloaded <= downloaded_bytes());
+        let lag: i64 = body
+            .lines()
+            .find_map(|line| line.strip_prefix("mbpg_light_replication_lag_seconds "))
+            .expect("lag gauge")
+            .parse()
+            .expect("lag seconds");
+        assert!((7200..7260).contains(&lag), "lag of {lag}s");
+        Ok(())
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test --features http -- --ignored test_serve`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_serve_status() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let mb_light = MbLight::try_new(crate::settings::Settings::default(), db_url).await?;
+        let server = mb_light.serve_http("127.0.0.1:39187").await?;
+        let control = ReplicationControl::get(&mb_light.db).await?;
+        mb_light.emit(MbLightEvent::Error("fixture".to_string()));
+
+        let client = reqwest::Client::new();
+        let healthz = client.get("http://127.0.0.1:39187/healthz").send().await?;
+        assert_eq!(healthz.status(), reqwest::StatusCode::OK);
+
+        let status: serde_json::Value = serde_json::from_str(
+            &client
+                .get("http://127.0.0.1:39187/status")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(
+            status["replication_sequence"],
+            serde_json::json!(control.current_replication_sequence)
+        );
+        assert_eq!(
+            status["schema_sequence"],
+            serde_json::json!(control.current_schema_sequence)
+        );
+        assert!(status["lag_seconds"].as_i64().is_some());
+
+        // The events are collected by another task
+        let mut metrics = String::new();
+        for _ in 0..50 {
+            metrics = client
+                .get("http://127.0.0.1:39187/metrics")
+                .send()
+                .await?
+                .text()
+                .await?;
+            if metrics.contains("mbpg_light_errors_total") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(metrics.contains(r#"mbpg_light_errors_total{kind="replication"} 1"#));
+        assert!(metrics.contains("mbpg_light_replication_lag_seconds "));
+
+        server.abort();
+        Ok(())
+    }
+}
@@ -1,23 +1,49 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::Path, sync::Arc, time::Duration};
 
+use crate::musicbrainz_db::import_state::{ImportState, ImportStep};
 use crate::musicbrainz_db::replication::replication_control::ReplicationControl;
-use crate::{error::MbLightResult, settings::MbLightSettingsExt};
+use crate::packet_source::{CachedPacketSource, HttpPacketSource, PacketSource};
+use crate::{
+    error::MbLightResult,
+    settings::{MbLightSettingsExt, PostSyncTrigger},
+    verify::Verifier,
+};
 use octocrab::Octocrab;
 use sqlx::PgPool;
 use sqlx::postgres::PgPoolOptions;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::broadcast;
 use tracing::{error, info};
 
 mod error;
+mod event;
+#[cfg(feature = "http")]
+mod http;
+mod parallel_bzip2;
 mod tar_helper;
 
 pub(crate) mod download;
 pub(crate) mod musicbrainz_db;
 pub(crate) mod progress;
 
+pub mod packet_source;
 pub mod settings;
+pub mod verify;
 
+pub use download::{downloaded_bytes, musicbrainz::DumpListing, retry::RetryPolicy};
 pub use error::MbLightError;
+pub use event::MbLightEvent;
+#[cfg(feature = "http")]
+pub use http::Metrics;
+pub use musicbrainz_db::dump_metadata::DumpMetadata;
+pub use musicbrainz_db::replication::changes::{
+    AppliedChanges, ReplicationChanges, TableChanges, TableRows,
+};
+pub use musicbrainz_db::replication::replication_control::{
+    ReplicationSequence, SchemaSequence, SchemaTransition,
+};
+
+/// Events buffered for each receiver of [`MbLight::subscribe`].
+pub const EVENT_CAPACITY: usize = 1024;
 
 pub struct MbLight<S: MbLightSettingsExt> {
     pub http_client: reqwest::Client,
@@ -25,77 +51,199 @@ pub struct MbLight<S: MbLightSettingsExt> {
     pub config: Arc<S>,
     pub db: PgPool,
     pub db_url: String,
-    pub reindex_sender: Option<Sender<()>>,
+    pub events: broadcast::Sender<MbLightEvent>,
+    pub packet_source: Arc<dyn PacketSource>,
+    pub verifier: Verifier,
 }
 
 impl<S: MbLightSettingsExt> MbLight<S> {
     pub async fn try_new(config: S, db_url: String) -> Result<Self, MbLightError> {
-        let db = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(&db_url)
-            .await?;
+        let db = pool_options(&config).connect(&db_url).await?;
 
+        let http_client = reqwest::Client::new();
+        let mut verifier =
+            Verifier::new(http_client.clone()).with_checksums(config.verify_checksums());
+        if let Some(public_key) = config.gpg_public_key() {
+            verifier = verifier.with_public_key(public_key);
+        }
+
+        let mut packet_source: Arc<dyn PacketSource> = Arc::new(
+            HttpPacketSource::new(
+                http_client.clone(),
+                config.musicbrainz_url(),
+                config.musicbrainz_token(),
+            )
+            .with_verifier(verifier.clone())
+            .with_retry_policy(config.retry_policy()),
+        );
+        if let Some(cache_dir) = config.packet_cache_dir() {
+            packet_source = Arc::new(
+                CachedPacketSource::new(cache_dir, packet_source)
+                    .with_retention(config.packet_cache_keep()),
+            );
+        }
+
+        let github_client = github_client(&config)?;
         Ok(Self {
-            http_client: reqwest::Client::new(),
+            http_client,
             config: Arc::new(config),
             db,
             db_url,
-            github_client: Octocrab::builder().build()?,
-            reindex_sender: None,
+            github_client,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            packet_source,
+            verifier,
         })
     }
 
     async fn reconnect(&mut self) -> MbLightResult<()> {
-        let db = PgPoolOptions::new()
-            .max_connections(5)
+        let db = pool_options(self.config.as_ref())
             .connect(&self.db_url)
             .await?;
         self.db = db;
         Ok(())
     }
 
-    pub fn with_sender(mut self, sender: Sender<()>) -> Self {
-        self.reindex_sender = Some(sender);
+    /// Receives the [`MbLightEvent`]s emitted from now on. A receiver lagging more than
+    /// [`EVENT_CAPACITY`] events behind misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<MbLightEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: MbLightEvent) {
+        // Nobody listening is not an error
+        let _ = self.events.send(event);
+    }
+
+    /// Replace the default [`HttpPacketSource`] replication packets are fetched from.
+    ///
+    /// The replacement is used as is, it is not wrapped in the configured packet cache.
+    pub fn with_packet_source(mut self, source: impl PacketSource + 'static) -> Self {
+        self.packet_source = Arc::new(source);
         self
     }
 
     /// Initialize the database by downloading and processing MusicBrainz SQL dump.
+    ///
+    /// Progress is checkpointed in `mblight_meta.import_state`, re-running `init`
+    /// after a failure resumes from the last unfinished step.
+    ///
+    /// The dump version pinned by [`MbLightSettingsExt::dump_version`] is imported, the
+    /// latest one otherwise.
     pub async fn init(&mut self) -> MbLightResult<()> {
-        let local_path = self.download_musicbrainz_sql().await?;
-        self.create_schemas().await?;
-        self.create_tables(&local_path).await?;
-        self.ingest_dump().await?;
-        self.run_all_scripts(local_path).await?;
+        match self.config.dump_version().map(str::to_string) {
+            Some(dump_version) => self.init_version(&dump_version).await,
+            None => {
+                let dump_version = self.get_latest().await?;
+                info!("Latest version: {}", dump_version);
+                let metadata = self.fetch_dump_metadata(&dump_version).await?;
+                self.run_init(&dump_version, &metadata, None).await
+            }
+        }
+    }
+
+    /// Initialize the database from the full export `dump_version`, like `20261014-001803`.
+    pub async fn init_version(&mut self, dump_version: &str) -> MbLightResult<()> {
+        let metadata = self.fetch_dump_metadata(dump_version).await?;
+        info!("Pinned version: {}", dump_version);
+        self.run_init(dump_version, &metadata, None).await
+    }
+
+    /// Initialize the database from the `mbdump*.tar.bz2` archives stored in `dump_dir`.
+    ///
+    /// The dump version and the replication sequences are read from `mbdump.tar.bz2`,
+    /// `replication_control` is set so `sync` continues from the dump.
+    pub async fn init_from_dir(&mut self, dump_dir: &Path) -> MbLightResult<()> {
+        let metadata = self.read_dump_metadata(dump_dir).await?;
+        info!(
+            "Dump version: {}, schema_sequence = {}, replication_sequence = {}",
+            metadata.version(),
+            metadata.schema_sequence,
+            metadata.replication_sequence
+        );
+        self.run_init(&metadata.version(), &metadata, Some(dump_dir))
+            .await?;
+        ReplicationControl::set_from_dump(&self.db, &metadata).await
+    }
+
+    async fn run_init(
+        &mut self,
+        dump_version: &str,
+        metadata: &DumpMetadata,
+        dump_dir: Option<&Path>,
+    ) -> MbLightResult<()> {
+        ImportState::create_table(&self.db).await?;
+        ImportState::check_dump_version(&self.db, dump_version).await?;
+
+        let local_path = self.download_musicbrainz_sql(metadata).await?;
+
+        if !ImportState::is_finished(&self.db, ImportStep::CreateSchemas).await? {
+            ImportState::start(&self.db, ImportStep::CreateSchemas, dump_version).await?;
+            self.create_schemas().await?;
+            ImportState::finish(&self.db, ImportStep::CreateSchemas).await?;
+        }
+
+        if !ImportState::is_finished(&self.db, ImportStep::CreateTables).await? {
+            ImportState::start(&self.db, ImportStep::CreateTables, dump_version).await?;
+            self.create_tables(&local_path).await?;
+            ImportState::finish(&self.db, ImportStep::CreateTables).await?;
+        }
+
+        match dump_dir {
+            Some(dump_dir) => self.ingest_dump_dir(dump_dir, dump_version).await?,
+            None => self.ingest_dump(dump_version).await?,
+        }
+
+        if !ImportState::is_finished(&self.db, ImportStep::RunScripts).await? {
+            ImportState::start(&self.db, ImportStep::RunScripts, dump_version).await?;
+            self.run_all_scripts(local_path).await?;
+            ImportState::finish(&self.db, ImportStep::RunScripts).await?;
+        }
+
         Ok(())
     }
 
     pub async fn sync(&self, infinite: bool) -> Result<(), MbLightError> {
         self.drop_tablecheck().await?;
+        let post_sync = self.config.post_sync().map(|post_sync| post_sync.after);
+        // Whether packets were applied since the post-sync steps last ran
+        let mut post_sync_pending = false;
         loop {
             match self.apply_pending_replication().await {
-                Ok(_) => {}
+                Ok(_) => {
+                    if post_sync == Some(PostSyncTrigger::Packet) {
+                        self.post_sync().await;
+                    } else {
+                        post_sync_pending = true;
+                    }
+                }
                 Err(MbLightError::NotFound) => {
-                    if let Some(sender) = &self.reindex_sender {
-                        info!("Reached last replication packet, sending reindex signal");
-                        sender.send(()).await?;
+                    let control = ReplicationControl::get(&self.db).await?;
+                    let sequence = control
+                        .current_replication_sequence
+                        .ok_or(MbLightError::MissingRepplicationSequence)?;
+                    self.emit(MbLightEvent::CaughtUp { sequence });
+                    if post_sync == Some(PostSyncTrigger::CaughtUp) && post_sync_pending {
+                        self.post_sync().await;
+                        post_sync_pending = false;
                     }
                     if infinite {
                         info!("Waiting for 15 minutes for a fresh replication packet");
                         tokio::time::sleep(Duration::from_secs(60 * 15)).await;
                     } else {
-                        let control = ReplicationControl::get(&self.db).await?;
                         info!(
                             "Reached last replication packet, schema_sequence = {}, replication_sequence = {}, terminating",
-                            control.current_schema_sequence.expect("schema sequence"),
                             control
-                                .current_replication_sequence
-                                .expect("replication sequence")
+                                .current_schema_sequence
+                                .ok_or(MbLightError::MissingSchemaSequence)?,
+                            sequence
                         );
                         return Ok(());
                     }
                 }
                 Err(err) => {
                     error!("Fatal error applying pending replication: {}", err);
+                    self.emit(MbLightEvent::Error(err.to_string()));
                     return Err(err);
                 }
             }
@@ -116,3 +264,18 @@ impl<S: MbLightSettingsExt> MbLight<S> {
         Ok(has_data)
     }
 }
+
+fn github_client(config: &impl MbLightSettingsExt) -> MbLightResult<Octocrab> {
+    let builder = Octocrab::builder();
+    let builder = match config.github_token() {
+        Some(token) => builder.personal_token(token.to_string()),
+        None => builder,
+    };
+    Ok(builder.build()?)
+}
+
+/// Each concurrent dump table `COPY` holds a connection, leave some room for other queries.
+fn pool_options(config: &impl MbLightSettingsExt) -> PgPoolOptions {
+    let connections = (config.ingest_concurrency() as u32 + 2).max(5);
+    PgPoolOptions::new().max_connections(connections)
+}
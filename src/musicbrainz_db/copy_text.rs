@@ -0,0 +1,150 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Reader adapter over a `COPY ... TO STDOUT` text stream, keeping only the rows
+/// whose `column` value satisfies `predicate`.
+pub struct CopyFilter<R, F> {
+    inner: BufReader<R>,
+    column: usize,
+    predicate: F,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read, F: FnMut(&str) -> bool> CopyFilter<R, F> {
+    pub fn new(inner: R, column: usize, predicate: F) -> Self {
+        Self {
+            inner: BufReader::with_capacity(1024 * 1024, inner),
+            column,
+            predicate,
+            line: vec![],
+            pos: 0,
+        }
+    }
+
+    fn keep_line(&mut self) -> bool {
+        let row = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        // Let postgres deal with malformed rows and NULL values
+        match split_row(row).nth(self.column).and_then(decode_field) {
+            Some(value) => (self.predicate)(&value),
+            None => true,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(&str) -> bool> Read for CopyFilter<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.line.len() {
+                let n = buf.len().min(self.line.len() - self.pos);
+                buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+
+            self.line.clear();
+            self.pos = 0;
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+
+            if !self.keep_line() {
+                self.line.clear();
+            }
+        }
+    }
+}
+
+/// Splits a `COPY` text row into its raw, still escaped, fields.
+pub fn split_row(row: &[u8]) -> impl Iterator<Item = &[u8]> {
+    row.split(|b| *b == b'\t')
+}
+
+/// Decodes a raw `COPY` text field, returning `None` for `\N` (NULL).
+pub fn decode_field(field: &[u8]) -> Option<String> {
+    if field == b"\\N" {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(field.len());
+    let mut bytes = field.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            decoded.push(b);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'b') => decoded.push(0x08),
+            Some(b'f') => decoded.push(0x0c),
+            Some(b'n') => decoded.push(b'\n'),
+            Some(b'r') => decoded.push(b'\r'),
+            Some(b't') => decoded.push(b'\t'),
+            Some(b'v') => decoded.push(0x0b),
+            Some(b'x') => {
+                let mut value = 0u8;
+                for _ in 0..2 {
+                    match bytes.peek().and_then(|d| (*d as char).to_digit(16)) {
+                        Some(digit) => {
+                            value = value.wrapping_mul(16).wrapping_add(digit as u8);
+                            bytes.next();
+                        }
+                        None => break,
+                    }
+                }
+                decoded.push(value);
+            }
+            Some(d @ b'0'..=b'7') => {
+                let mut value = d - b'0';
+                for _ in 0..2 {
+                    match bytes.peek() {
+                        Some(d @ b'0'..=b'7') => {
+                            value = value.wrapping_mul(8).wrapping_add(d - b'0');
+                            bytes.next();
+                        }
+                        _ => break,
+                    }
+                }
+                decoded.push(value);
+            }
+            Some(other) => decoded.push(other),
+            None => decoded.push(b'\\'),
+        }
+    }
+
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_field() {
+        assert_eq!(decode_field(b"\\N"), None);
+        assert_eq!(decode_field(b"plain").unwrap(), "plain");
+        assert_eq!(
+            decode_field(b"a\\tb\\nc\\\\d\\101\\x42").unwrap(),
+            "a\tb\nc\\dAB"
+        );
+    }
+
+    #[test]
+    fn test_filter_rows() -> io::Result<()> {
+        let copy = "1\tmusicbrainz.artist\ti\n\
+                    2\tmusicbrainz.edit\ti\n\
+                    3\tmusicbrainz.artist\t{\"a\\tb\"}\n\
+                    4\t\\N\tu";
+
+        let mut filtered = String::new();
+        CopyFilter::new(copy.as_bytes(), 1, |table: &str| {
+            table != "musicbrainz.edit"
+        })
+        .read_to_string(&mut filtered)?;
+
+        assert_eq!(
+            filtered,
+            "1\tmusicbrainz.artist\ti\n3\tmusicbrainz.artist\t{\"a\\tb\"}\n4\t\\N\tu"
+        );
+        Ok(())
+    }
+}
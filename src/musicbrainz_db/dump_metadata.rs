@@ -0,0 +1,122 @@
+use std::{fs::File, io::Read, path::Path};
+
+use sqlx::types::chrono::{DateTime, Utc};
+
+use crate::{
+    MbLightError,
+    error::MbLightResult,
+    musicbrainz_db::replication::{
+        read_sequence, read_timestamp,
+        replication_control::{ReplicationSequence, SchemaSequence},
+    },
+    tar_helper::read_archive,
+};
+
+/// The `TIMESTAMP`, `SCHEMA_SEQUENCE` and `REPLICATION_SEQUENCE` entries of a dump archive,
+/// which replication packets start with as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpMetadata {
+    pub timestamp: DateTime<Utc>,
+    pub schema_sequence: SchemaSequence,
+    pub replication_sequence: ReplicationSequence,
+}
+
+impl DumpMetadata {
+    /// Reads the metadata entries of the archive at `path`, they come before the table data
+    /// so the rest of the archive is not decompressed.
+    pub fn read(path: &Path, bzip2_threads: usize) -> MbLightResult<Self> {
+        Self::from_reader(File::open(path)?, bzip2_threads)
+    }
+
+    /// Same as [`DumpMetadata::read`] for an archive read from `input`, which is not read
+    /// past the metadata entries.
+    pub fn from_reader(
+        input: impl Read + Send + 'static,
+        bzip2_threads: usize,
+    ) -> MbLightResult<Self> {
+        let (mut timestamp, mut schema_sequence, mut replication_sequence) = (None, None, None);
+        for entry in read_archive(input, bzip2_threads).entries()? {
+            let entry = entry?;
+            match entry.path()?.to_str() {
+                Some("TIMESTAMP") => timestamp = Some(read_timestamp(entry)?),
+                Some("SCHEMA_SEQUENCE") => {
+                    schema_sequence = Some(SchemaSequence(read_sequence(entry)?))
+                }
+                Some("REPLICATION_SEQUENCE") => {
+                    replication_sequence = Some(ReplicationSequence(read_sequence(entry)?))
+                }
+                _ => {}
+            }
+
+            if let (Some(timestamp), Some(schema_sequence), Some(replication_sequence)) =
+                (timestamp, schema_sequence, replication_sequence)
+            {
+                return Ok(Self {
+                    timestamp,
+                    schema_sequence,
+                    replication_sequence,
+                });
+            }
+        }
+
+        let missing = match (timestamp, schema_sequence) {
+            (None, _) => "TIMESTAMP",
+            (_, None) => "SCHEMA_SEQUENCE",
+            _ => "REPLICATION_SEQUENCE",
+        };
+        Err(MbLightError::MissingDumpMetadata(missing))
+    }
+
+    /// Dump version, named like the directories of the MusicBrainz full export.
+    pub fn version(&self) -> String {
+        self.timestamp.format("%Y%m%d-%H%M%S").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_archive(path: &Path, entries: &[(&str, &str)]) -> std::io::Result<()> {
+        let encoder =
+            bzip2::write::BzEncoder::new(std::fs::File::create(path)?, bzip2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_dump_metadata() -> MbLightResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mbdump.tar.bz2");
+        write_archive(
+            &path,
+            &[
+                ("COPYING", "CC0"),
+                ("TIMESTAMP", "2026-10-14 00:18:03.465473+00\n"),
+                ("SCHEMA_SEQUENCE", "30\n"),
+                ("REPLICATION_SEQUENCE", "178120\n"),
+                ("mbdump/artist", "1\tname\n"),
+            ],
+        )?;
+
+        let metadata = DumpMetadata::read(&path, 1)?;
+        assert_eq!(metadata.schema_sequence, SchemaSequence(30));
+        assert_eq!(metadata.replication_sequence, ReplicationSequence(178120));
+        assert_eq!(metadata.version(), "20261014-001803");
+
+        write_archive(&path, &[("TIMESTAMP", "2026-10-14 00:18:03+00")])?;
+        assert!(matches!(
+            DumpMetadata::read(&path, 1),
+            Err(MbLightError::MissingDumpMetadata("SCHEMA_SEQUENCE"))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use sqlx::PgPool;
+use tracing::info;
+
+use crate::{MbLightError, error::MbLightResult};
+
+const COPY_PHASE: &str = "copy";
+
+/// A checkpointed unit of work performed by `MbLight::init`.
+#[derive(Debug, Clone, Copy)]
+pub enum ImportStep<'a> {
+    CreateSchemas,
+    CreateTables,
+    /// A full dump archive, once every table it contains has been copied.
+    Archive(&'a str),
+    /// A single `COPY` of a dump table.
+    Copy {
+        schema: &'a str,
+        table: &'a str,
+    },
+    RunScripts,
+}
+
+impl ImportStep<'_> {
+    fn phase(&self) -> &'static str {
+        match self {
+            ImportStep::CreateSchemas => "create_schemas",
+            ImportStep::CreateTables => "create_tables",
+            ImportStep::Archive(_) => "archive",
+            ImportStep::Copy { .. } => COPY_PHASE,
+            ImportStep::RunScripts => "run_scripts",
+        }
+    }
+
+    fn target(&self) -> String {
+        match self {
+            ImportStep::Archive(name) => name.to_string(),
+            ImportStep::Copy { schema, table } => format!("{schema}.{table}"),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum ImportStatus {
+    Started,
+    Finished,
+}
+
+/// Checkpoints of `MbLight::init`, persisted in `mblight_meta.import_state`.
+pub struct ImportState;
+
+impl ImportState {
+    pub async fn create_table(db: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::raw_sql(
+            r#"CREATE SCHEMA IF NOT EXISTS mblight_meta;
+               CREATE TABLE IF NOT EXISTS mblight_meta.import_state (
+                   phase TEXT NOT NULL,
+                   target TEXT NOT NULL DEFAULT '',
+                   dump_version TEXT NOT NULL,
+                   status TEXT NOT NULL,
+                   started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                   finished_at TIMESTAMPTZ,
+                   PRIMARY KEY (phase, target)
+               );"#,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Fails if a previous, unfinished import was started from another dump version. The
+    /// checkpoints of a finished import of another version are cleared.
+    pub async fn check_dump_version(db: &PgPool, dump_version: &str) -> MbLightResult<()> {
+        let recorded: Vec<(String, ImportStatus)> =
+            sqlx::query_as("SELECT DISTINCT dump_version, status FROM mblight_meta.import_state")
+                .fetch_all(db)
+                .await?;
+
+        if ensure_same_dump_version(&recorded, dump_version)? {
+            sqlx::query("DELETE FROM mblight_meta.import_state WHERE dump_version <> $1")
+                .bind(dump_version)
+                .execute(db)
+                .await?;
+            info!("Cleared the checkpoints of the previous import");
+        }
+        Ok(())
+    }
+
+    pub async fn status(
+        db: &PgPool,
+        step: ImportStep<'_>,
+    ) -> Result<Option<ImportStatus>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT status FROM mblight_meta.import_state WHERE phase = $1 AND target = $2",
+        )
+        .bind(step.phase())
+        .bind(step.target())
+        .fetch_optional(db)
+        .await
+    }
+
+    pub async fn is_finished(db: &PgPool, step: ImportStep<'_>) -> Result<bool, sqlx::Error> {
+        Ok(Self::status(db, step).await? == Some(ImportStatus::Finished))
+    }
+
+    /// Tables whose copy finished, as `schema.table`.
+    pub async fn finished_copies(db: &PgPool) -> Result<HashSet<String>, sqlx::Error> {
+        let copies: Vec<String> = sqlx::query_scalar(
+            "SELECT target FROM mblight_meta.import_state WHERE phase = $1 AND status = $2",
+        )
+        .bind(COPY_PHASE)
+        .bind(ImportStatus::Finished)
+        .fetch_all(db)
+        .await?;
+        Ok(copies.into_iter().collect())
+    }
+
+    pub async fn start(
+        db: &PgPool,
+        step: ImportStep<'_>,
+        dump_version: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT INTO mblight_meta.import_state (phase, target, dump_version, status)
+               VALUES ($1, $2, $3, $4)
+               ON CONFLICT (phase, target) DO UPDATE
+               SET dump_version = EXCLUDED.dump_version,
+                   status = EXCLUDED.status,
+                   started_at = NOW(),
+                   finished_at = NULL"#,
+        )
+        .bind(step.phase())
+        .bind(step.target())
+        .bind(dump_version)
+        .bind(ImportStatus::Started)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn finish(db: &PgPool, step: ImportStep<'_>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE mblight_meta.import_state
+               SET status = $3, finished_at = NOW()
+               WHERE phase = $1 AND target = $2"#,
+        )
+        .bind(step.phase())
+        .bind(step.target())
+        .bind(ImportStatus::Finished)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Checks the `recorded` checkpoints, by dump version and status, allow importing
+/// `dump_version`. Returns whether checkpoints of another, finished, import are left.
+fn ensure_same_dump_version(
+    recorded: &[(String, ImportStatus)],
+    dump_version: &str,
+) -> MbLightResult<bool> {
+    let mut previous = recorded
+        .iter()
+        .filter(|(v, _)| v != dump_version)
+        .peekable();
+    if previous.peek().is_none() {
+        return Ok(false);
+    }
+
+    match previous.find(|(_, status)| *status != ImportStatus::Finished) {
+        Some((unfinished, _)) => Err(MbLightError::DumpVersionMissmatch {
+            expected: unfinished.clone(),
+            got: dump_version.to_string(),
+        }),
+        None => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_with_same_dump_version() {
+        let recorded = vec![
+            ("20261014-001".to_string(), ImportStatus::Finished),
+            ("20261014-001".to_string(), ImportStatus::Started),
+        ];
+        assert!(!ensure_same_dump_version(&recorded, "20261014-001").unwrap());
+        assert!(!ensure_same_dump_version(&[], "20261014-001").unwrap());
+    }
+
+    #[test]
+    fn test_new_dump_version_after_finished_import() {
+        let recorded = vec![("20261011-001".to_string(), ImportStatus::Finished)];
+        assert!(ensure_same_dump_version(&recorded, "20261014-001").unwrap());
+    }
+
+    #[test]
+    fn test_refuse_mixing_dump_versions() {
+        let recorded = vec![
+            ("20261011-001".to_string(), ImportStatus::Finished),
+            ("20261011-001".to_string(), ImportStatus::Started),
+        ];
+        let err = ensure_same_dump_version(&recorded, "20261014-001").unwrap_err();
+        assert!(matches!(
+            err,
+            MbLightError::DumpVersionMissmatch { expected, got }
+                if expected == "20261011-001" && got == "20261014-001"
+        ));
+    }
+}
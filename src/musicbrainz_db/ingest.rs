@@ -0,0 +1,561 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use indicatif::MultiProgress;
+use reqwest::StatusCode;
+use tempfile::NamedTempFile;
+use tokio::{
+    io::AsyncReadExt,
+    sync::{mpsc, oneshot},
+};
+use tracing::info;
+
+use crate::{
+    MbLight, MbLightError, MbLightEvent,
+    download::{body_reader::BodyReader, record_downloaded},
+    error::MbLightResult,
+    musicbrainz_db::import_state::{ImportState, ImportStatus, ImportStep},
+    progress::get_progress_bar,
+    settings::MbLightSettingsExt,
+    tar_helper::read_archive,
+    verify::{ChecksumAlgorithm, ensure_digest, to_hex},
+};
+
+#[cfg(not(test))]
+const CHUNK_SIZE: usize = 1024 * 1024;
+#[cfg(test)]
+const CHUNK_SIZE: usize = 1024;
+/// Decoded chunks buffered per table, past them the table is spooled to a temporary file
+/// while its COPY catches up.
+const CHUNKS_PER_TABLE: usize = 32;
+/// Bytes spooled per table, past them the decoder waits for the COPY.
+const SPOOL_LIMIT: usize = 512 * CHUNK_SIZE;
+/// Downloaded chunks buffered while streaming an archive, ahead of the decoder.
+const BODY_CHUNKS: usize = 64;
+
+/// A dump table found in the archive, its data is sent over `chunks` as it is decoded.
+struct TableData {
+    schema: String,
+    table: String,
+    size: u64,
+    chunks: mpsc::Receiver<io::Result<TableChunk>>,
+}
+
+/// Data of a table, in order: decoded chunks, the part spooled when the COPY fell behind,
+/// then decoded chunks again once the spool is full.
+enum TableChunk {
+    Data(Bytes),
+    Spooled(NamedTempFile),
+}
+
+enum TableSource {
+    Channel(mpsc::Receiver<io::Result<TableChunk>>),
+    Spool {
+        file: tokio::fs::File,
+        // Removed once read
+        _spool: NamedTempFile,
+        chunks: mpsc::Receiver<io::Result<TableChunk>>,
+    },
+}
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Copies every table of a dump archive, the archive is decoded on a blocking thread
+    /// while up to [`MbLightSettingsExt::ingest_concurrency`] tables are copied at once. The
+    /// tar entries are read in order, the decoder spools up to [`SPOOL_LIMIT`] of a table
+    /// whose COPY falls behind to move on to the next ones. Skipped and already copied tables
+    /// are not decoded.
+    pub(crate) async fn ingest_archive(
+        &self,
+        path: &Path,
+        dump_version: &str,
+    ) -> MbLightResult<()> {
+        self.ingest_reader(File::open(path)?, dump_version, true)
+            .await?;
+        Ok(())
+    }
+
+    /// Same as [`MbLight::ingest_archive`] for an archive read from `input`, returns the
+    /// tables that were copied. Unless `verified`, the copies are left `Started` until
+    /// [`MbLight::finish_tables`] records them.
+    async fn ingest_reader(
+        &self,
+        input: impl Read + Send + 'static,
+        dump_version: &str,
+        verified: bool,
+    ) -> MbLightResult<Vec<String>> {
+        let wanted = self.wanted_tables().await?;
+        let (tables_tx, mut tables_rx) = mpsc::channel(1);
+        let bzip2_threads = self.config.bzip2_threads();
+        let decoder = tokio::task::spawn_blocking(move || {
+            decode_archive(input, bzip2_threads, &wanted, tables_tx)
+        });
+
+        let mp = MultiProgress::new();
+        // The receivers are dropped with the stream on the first error, which stops the decoder
+        let copied = stream::poll_fn(move |cx| tables_rx.poll_recv(cx))
+            .map(|data| self.ingest_table(data, dump_version, verified, &mp))
+            .buffer_unordered(self.config.ingest_concurrency().max(1))
+            .try_collect::<Vec<_>>()
+            .await;
+
+        // A decoding error is the root cause of the copy errors it triggers
+        decoder.await.map_err(io::Error::other)??;
+        Ok(copied?.into_iter().flatten().collect())
+    }
+
+    /// Ingests the archive at `url` while it downloads, the body is never stored on disk.
+    ///
+    /// A published checksum can only be compared once the whole archive went through, the
+    /// tables copied from it are only finished then. Until they are, an interrupted or failed
+    /// stream leaves them to be copied again by the next run. A body that fails to download
+    /// is reported as [`MbLightError::StreamInterrupted`], whatever decoding error it caused.
+    pub(crate) async fn stream_archive(
+        &self,
+        url: &str,
+        filename: &str,
+        dump_version: &str,
+        checksum: Option<(ChecksumAlgorithm, String)>,
+    ) -> MbLightResult<()> {
+        let response = self.http_client.get(url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(MbLightError::NotFound);
+        }
+        let response = response.error_for_status()?;
+        let pb = get_progress_bar(response.content_length().unwrap_or(0))?;
+        pb.set_message(format!("Streaming {filename}"));
+
+        let (body_tx, body_rx) = mpsc::channel(BODY_CHUNKS);
+        let (failed_tx, mut failed) = oneshot::channel();
+        let mut hasher = checksum.as_ref().map(|(algorithm, _)| algorithm.hasher());
+        let mut body = response.bytes_stream();
+        // Reads the body to its end even if the decoder stops early, so it can be hashed,
+        // unless the ingestion failed
+        let download = async move {
+            loop {
+                let chunk = tokio::select! {
+                    chunk = body.next() => chunk,
+                    Ok(()) = &mut failed => return Ok(None),
+                };
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                let chunk = chunk.map_err(|source| MbLightError::StreamInterrupted {
+                    file: filename.to_string(),
+                    source,
+                })?;
+                pb.inc(chunk.len() as u64);
+                record_downloaded(chunk.len());
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&chunk);
+                }
+                let _ = body_tx.send(chunk).await;
+            }
+            pb.finish();
+            Ok::<_, MbLightError>(hasher.map(|hasher| to_hex(&hasher.finalize())))
+        };
+
+        let reader = BodyReader::new(body_rx);
+        let ingest = async {
+            let copied = self.ingest_reader(reader, dump_version, false).await;
+            if copied.is_err() {
+                let _ = failed_tx.send(());
+            }
+            copied
+        };
+        // An interrupted download is the root cause of the decoding errors it triggers
+        let (digest, copied) = tokio::join!(download, ingest);
+        let (digest, copied) = (digest?, copied?);
+
+        if let (Some((_, expected)), Some(got)) = (checksum, digest) {
+            ensure_digest(filename, &expected, got)?;
+            info!("{filename} checksum verified");
+        }
+
+        self.finish_tables(&copied).await
+    }
+
+    /// Records the copies of `tables` as finished, once their archive is verified.
+    async fn finish_tables(&self, tables: &[String]) -> MbLightResult<()> {
+        for fulltable in tables {
+            let (schema, table) = fulltable.split_once('.').unwrap_or_default();
+            ImportState::finish(&self.db, ImportStep::Copy { schema, table }).await?;
+            self.emit(MbLightEvent::TableIngested {
+                schema: schema.to_string(),
+                table: table.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The existing tables, as `schema.table`, that are kept and not copied yet.
+    async fn wanted_tables(&self) -> MbLightResult<HashSet<String>> {
+        let tables: Vec<(String, String)> = sqlx::query_as(
+            "SELECT table_schema::text, table_name::text FROM information_schema.tables WHERE table_type = 'BASE TABLE'",
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let copied = ImportState::finished_copies(&self.db).await?;
+
+        Ok(tables
+            .into_iter()
+            .filter(|(schema, table)| {
+                !self.config.should_skip_schema(schema) && !self.config.should_skip_table(table)
+            })
+            .map(|(schema, table)| format!("{schema}.{table}"))
+            .filter(|fulltable| !copied.contains(fulltable))
+            .collect())
+    }
+
+    async fn ingest_table(
+        &self,
+        data: TableData,
+        dump_version: &str,
+        verified: bool,
+        mp: &MultiProgress,
+    ) -> MbLightResult<Option<String>> {
+        let TableData {
+            schema,
+            table,
+            size,
+            chunks,
+        } = data;
+        let (schema, table) = (schema.as_str(), table.as_str());
+
+        let step = ImportStep::Copy { schema, table };
+        match ImportState::status(&self.db, step).await? {
+            Some(ImportStatus::Finished) => {
+                info!("Skipping {schema}.{table} (already imported)");
+                return Ok(None);
+            }
+            _ if self.has_data(schema, table).await? => {
+                info!("Truncating partially loaded table {schema}.{table}");
+                sqlx::query(&format!("TRUNCATE TABLE {schema}.{table}"))
+                    .execute(&self.db)
+                    .await?;
+            }
+            _ => {}
+        }
+
+        let pb = mp.add(get_progress_bar(size)?);
+        pb.set_message(table.to_string());
+
+        let progress = pb.clone();
+        let chunks = Box::pin(table_chunks(chunks));
+        let chunks = stream::unfold((chunks, 0), move |(mut chunks, received)| {
+            let pb = progress.clone();
+            async move {
+                match chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        let len = chunk.len() as u64;
+                        pb.inc(len);
+                        Some((Ok(chunk), (chunks, received + len)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (chunks, received))),
+                    // The decoder went away before the end of the table
+                    None if received < size => Some((
+                        Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "archive decoding stopped",
+                        )),
+                        (chunks, size),
+                    )),
+                    None => None,
+                }
+            }
+        });
+
+        ImportState::start(&self.db, step, dump_version).await?;
+        self.pg_copy_chunks(chunks, schema, table, pb).await?;
+        let fulltable = format!("{schema}.{table}");
+        if verified {
+            self.finish_tables(std::slice::from_ref(&fulltable)).await?;
+        }
+        Ok(Some(fulltable))
+    }
+}
+
+/// Reads the `mbdump/` entries of the archive, sending each `wanted` table and its data to
+/// `tables`.
+fn decode_archive(
+    input: impl Read + Send + 'static,
+    bzip2_threads: usize,
+    wanted: &HashSet<String>,
+    tables: mpsc::Sender<TableData>,
+) -> MbLightResult<()> {
+    let mut archive = read_archive(input, bzip2_threads);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let Some(filename) = name.strip_prefix("mbdump/") else {
+            continue;
+        };
+
+        let filename = filename.strip_suffix("_sanitised").unwrap_or(filename);
+        let (schema, table) = filename
+            .split_once('.')
+            .unwrap_or(("musicbrainz", filename));
+        if !wanted.contains(&format!("{schema}.{table}")) {
+            info!("Skipping {schema}.{table}");
+            continue;
+        }
+
+        // One more slot for the spool file, so the decoder never waits on an unread table
+        let (chunks_tx, chunks) = mpsc::channel(CHUNKS_PER_TABLE + 1);
+        let data = TableData {
+            schema: schema.to_string(),
+            table: table.to_string(),
+            size: entry.size(),
+            chunks,
+        };
+
+        if tables.blocking_send(data).is_err() {
+            // Ingestion failed, the error is reported by the copy
+            return Ok(());
+        }
+
+        let mut spool: Option<(NamedTempFile, usize)> = None;
+        // Once the spool is full, the rest of the table waits for the COPY
+        let mut spooled = false;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            match (&mut entry).take(CHUNK_SIZE as u64).read_to_end(&mut chunk) {
+                Ok(0) => break,
+                Ok(_) => {
+                    if chunks_tx.is_closed() {
+                        // Failed table, move on to the next entry
+                        spool = None;
+                        break;
+                    }
+
+                    if let Some((file, len)) = spool.as_mut() {
+                        file.write_all(&chunk)?;
+                        *len += chunk.len();
+                        if *len >= SPOOL_LIMIT {
+                            if let Some((file, _)) = spool.take() {
+                                send_spool(&chunks_tx, file)?;
+                            }
+                            spooled = true;
+                        }
+                    } else if spooled || chunks_tx.capacity() > 1 {
+                        let chunk = Ok(TableChunk::Data(Bytes::from(chunk)));
+                        if chunks_tx.blocking_send(chunk).is_err() {
+                            break;
+                        }
+                    } else {
+                        // The COPY fell behind, spool the table for a while
+                        let mut file = NamedTempFile::new()?;
+                        file.write_all(&chunk)?;
+                        spool = Some((file, chunk.len()));
+                    }
+                }
+                Err(e) => {
+                    let _ = chunks_tx.blocking_send(Err(io::Error::new(e.kind(), e.to_string())));
+                    return Err(e.into());
+                }
+            }
+        }
+
+        if let Some((file, _)) = spool {
+            send_spool(&chunks_tx, file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Hands a spool file over, in the slot kept for it.
+fn send_spool(
+    chunks: &mpsc::Sender<io::Result<TableChunk>>,
+    mut file: NamedTempFile,
+) -> io::Result<()> {
+    file.flush()?;
+    // A closed channel is a failed table
+    let _ = chunks.try_send(Ok(TableChunk::Spooled(file)));
+    Ok(())
+}
+
+/// The data of a table, from its channel and its spool file.
+fn table_chunks(
+    chunks: mpsc::Receiver<io::Result<TableChunk>>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(
+        Some(TableSource::Channel(chunks)),
+        |mut source| async move {
+            loop {
+                match source.take()? {
+                    TableSource::Channel(mut chunks) => match chunks.recv().await? {
+                        Ok(TableChunk::Data(chunk)) => {
+                            return Some((Ok(chunk), Some(TableSource::Channel(chunks))));
+                        }
+                        Ok(TableChunk::Spooled(spool)) => match spool.reopen() {
+                            Ok(file) => {
+                                source = Some(TableSource::Spool {
+                                    file: tokio::fs::File::from_std(file),
+                                    _spool: spool,
+                                    chunks,
+                                })
+                            }
+                            Err(e) => return Some((Err(e), None)),
+                        },
+                        Err(e) => return Some((Err(e), None)),
+                    },
+                    TableSource::Spool {
+                        mut file,
+                        _spool,
+                        chunks,
+                    } => {
+                        let mut chunk = vec![0; CHUNK_SIZE];
+                        match file.read(&mut chunk).await {
+                            // The rest of the table, if any, follows in the channel
+                            Ok(0) => source = Some(TableSource::Channel(chunks)),
+                            Ok(n) => {
+                                chunk.truncate(n);
+                                return Some((
+                                    Ok(Bytes::from(chunk)),
+                                    Some(TableSource::Spool {
+                                        file,
+                                        _spool,
+                                        chunks,
+                                    }),
+                                ));
+                            }
+                            Err(e) => return Some((Err(e), None)),
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn archive(entries: &[(&str, Vec<u8>)]) -> io::Result<Vec<u8>> {
+        let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_slice())?;
+        }
+        builder.into_inner()?.finish()
+    }
+
+    fn wanted(tables: &[&str]) -> HashSet<String> {
+        tables.iter().map(|table| table.to_string()).collect()
+    }
+
+    async fn read(data: TableData) -> io::Result<Vec<u8>> {
+        table_chunks(data.chunks)
+            .try_fold(Vec::new(), |mut all, chunk| async move {
+                all.extend_from_slice(&chunk);
+                Ok(all)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_tables_are_fed_concurrently() -> MbLightResult<()> {
+        let artist: Vec<u8> = (0..CHUNK_SIZE * CHUNKS_PER_TABLE * 3)
+            .map(|i| b"0123456789\t\n"[i % 12])
+            .collect();
+        let release = b"1\trelease\n".repeat(100);
+        let input = archive(&[
+            ("mbdump/artist", artist.clone()),
+            ("mbdump/release", release.clone()),
+        ])?;
+
+        let (tables_tx, mut tables) = mpsc::channel(1);
+        let wanted = wanted(&["musicbrainz.artist", "musicbrainz.release"]);
+        let decoder = tokio::task::spawn_blocking(move || {
+            decode_archive(io::Cursor::new(input), 1, &wanted, tables_tx)
+        });
+
+        // The second table is handed out while nothing of the first one was read
+        let first = tables.recv().await.expect("artist");
+        let second = tokio::time::timeout(Duration::from_secs(10), tables.recv())
+            .await
+            .expect("the decoder waits for the first table")
+            .expect("release");
+        assert_eq!(
+            (first.table.as_str(), second.table.as_str()),
+            ("artist", "release")
+        );
+
+        let (second, first) = tokio::try_join!(read(second), read(first))?;
+        assert_eq!(first, artist);
+        assert_eq!(second, release);
+        decoder.await.map_err(io::Error::other)??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_is_capped() -> MbLightResult<()> {
+        let artist: Vec<u8> = (0..CHUNK_SIZE * CHUNKS_PER_TABLE + SPOOL_LIMIT * 2)
+            .map(|i| b"0123456789\t\n"[i % 12])
+            .collect();
+        let release = b"1\trelease\n".repeat(100);
+        let input = archive(&[
+            ("mbdump/artist", artist.clone()),
+            ("mbdump/release", release.clone()),
+        ])?;
+
+        let (tables_tx, mut tables) = mpsc::channel(1);
+        let wanted = wanted(&["musicbrainz.artist", "musicbrainz.release"]);
+        let decoder = tokio::task::spawn_blocking(move || {
+            decode_archive(io::Cursor::new(input), 1, &wanted, tables_tx)
+        });
+
+        // Past the spool limit, the decoder waits for the first table to be read
+        let first = tables.recv().await.expect("artist");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), tables.recv())
+                .await
+                .is_err()
+        );
+        let (first, second) = tokio::join!(read(first), async {
+            read(tables.recv().await.expect("release")).await
+        });
+        assert_eq!(first?, artist);
+        assert_eq!(second?, release);
+        decoder.await.map_err(io::Error::other)??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unwanted_tables_are_not_decoded() -> MbLightResult<()> {
+        let input = archive(&[
+            ("mbdump/artist", b"1\tartist\n".to_vec()),
+            ("mbdump/cover_art_archive.art_type", b"1\tFront\n".to_vec()),
+            ("mbdump/release", b"1\trelease\n".to_vec()),
+        ])?;
+
+        let (tables_tx, mut tables) = mpsc::channel(1);
+        let wanted = wanted(&["musicbrainz.release"]);
+        let decoder = tokio::task::spawn_blocking(move || {
+            decode_archive(io::Cursor::new(input), 1, &wanted, tables_tx)
+        });
+
+        let release = tables.recv().await.expect("release");
+        assert_eq!(
+            (release.schema.as_str(), release.table.as_str()),
+            ("musicbrainz", "release")
+        );
+        assert_eq!(read(release).await?, b"1\trelease\n");
+        assert!(tables.recv().await.is_none());
+        decoder.await.map_err(io::Error::other)??;
+        Ok(())
+    }
+}
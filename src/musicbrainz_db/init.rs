@@ -1,12 +1,13 @@
 use std::path::Path;
 
 use crate::error::MbLightResult;
-use crate::progress::get_progress_bar;
+use crate::musicbrainz_db::dump_metadata::DumpMetadata;
+use crate::musicbrainz_db::import_state::{ImportState, ImportStep};
 use crate::settings::MbLightSettingsExt;
-use crate::{MbLight, download::musicbrainz::MUSICBRAINZ_FTP, tar_helper::get_archive};
+use crate::{MbLight, MbLightError};
 use std::path::PathBuf;
-use tempfile::NamedTempFile;
-use tracing::{error, info};
+use tempfile::env::temp_dir;
+use tracing::{info, warn};
 
 const MB_DUMP: &str = "mbdump.tar.bz2";
 const MB_DUMP_DERIVED: &str = "mbdump-derived.tar.bz2";
@@ -14,19 +15,63 @@ const COVER_ART_ARCHIVE: &str = "mbdump-cover-art-archive.tar.bz2";
 const EVENT_ART_ARCHIVE: &str = "mbdump-even-art-archive.tar.bz2";
 const MB_DUMP_STATS: &str = "mbdump-stats.tar.bz2";
 
+/// Schemas of the MusicBrainz database, in creation order.
+pub(crate) const SCHEMAS: &[&str] = &[
+    "musicbrainz",
+    "cover_art_archive",
+    "event_art_archive",
+    "statistics",
+    "documentation",
+    "wikidocs",
+    "dbmirror2",
+];
+
+/// Scripts of `admin/sql` run before creating the tables, whatever the skipped schemas.
+pub(crate) const EXTENSION_SCRIPTS: &[&str] = &["Extensions.sql", "CreateSearchConfiguration.sql"];
+
+/// Scripts of `admin/sql` creating the types and tables of each schema.
+pub(crate) const TABLE_SCRIPTS: &[(&str, &str)] = &[
+    // types
+    ("musicbrainz", "CreateCollations.sql"),
+    ("musicbrainz", "CreateTypes.sql"),
+    // tables
+    ("musicbrainz", "CreateTables.sql"),
+    ("cover_art_archive", "caa/CreateTables.sql"),
+    ("event_art_archive", "eaa/CreateTables.sql"),
+    ("statistics", "statistics/CreateTables.sql"),
+    ("documentation", "documentation/CreateTables.sql"),
+    ("wikidocs", "wikidocs/CreateTables.sql"),
+];
+
+/// Scripts of `admin/sql` run once the dump is imported: keys, functions, indexes, views,
+/// triggers and replication setup.
+pub(crate) const SETUP_SCRIPTS: &[(&str, &str)] = &[
+    ("musicbrainz", "CreatePrimaryKeys.sql"),
+    ("cover_art_archive", "caa/CreatePrimaryKeys.sql"),
+    ("event_art_archive", "eaa/CreatePrimaryKeys.sql"),
+    ("statistics", "statistics/CreatePrimaryKeys.sql"),
+    ("documentation", "documentation/CreatePrimaryKeys.sql"),
+    ("wikidocs", "wikidocs/CreatePrimaryKeys.sql"),
+    ("musicbrainz", "CreateFunctions.sql"),
+    ("musicbrainz", "CreateMirrorOnlyFunctions.sql"),
+    ("cover_art_archive", "caa/CreateFunctions.sql"),
+    ("event_art_archive", "eaa/CreateFunctions.sql"),
+    ("musicbrainz", "CreateIndexes.sql"),
+    ("musicbrainz", "CreateMirrorIndexes.sql"),
+    ("cover_art_archive", "caa/CreateIndexes.sql"),
+    ("event_art_archive", "eaa/CreateIndexes.sql"),
+    ("statistics", "statistics/CreateIndexes.sql"),
+    ("musicbrainz", "CreateViews.sql"),
+    ("cover_art_archive", "caa/CreateViews.sql"),
+    ("event_art_archive", "eaa/CreateViews.sql"),
+    ("musicbrainz", "CreateMirrorOnlyTriggers.sql"),
+    ("musicbrainz", "ReplicationSetup.sql"),
+    ("dbmirror2", "dbmirror2/ReplicationSetup.sql"),
+];
+
 impl<S: MbLightSettingsExt> MbLight<S> {
     pub async fn create_schemas(&mut self) -> MbLightResult<()> {
-        let schemas = [
-            "musicbrainz",
-            "cover_art_archive",
-            "event_art_archive",
-            "statistics",
-            "documentation",
-            "wikidocs",
-            "dbmirror2",
-        ];
-
-        for schema in schemas {
+        for schema in SCHEMAS {
             if self.config.should_skip_schema(schema) {
                 continue;
             }
@@ -42,31 +87,7 @@ impl<S: MbLightSettingsExt> MbLight<S> {
     }
 
     pub async fn run_all_scripts(&mut self, local_path: PathBuf) -> MbLightResult<()> {
-        let sql_scripts = vec![
-            ("musicbrainz", "CreatePrimaryKeys.sql"),
-            ("cover_art_archive", "caa/CreatePrimaryKeys.sql"),
-            ("event_art_archive", "eaa/CreatePrimaryKeys.sql"),
-            ("statistics", "statistics/CreatePrimaryKeys.sql"),
-            ("documentation", "documentation/CreatePrimaryKeys.sql"),
-            ("wikidocs", "wikidocs/CreatePrimaryKeys.sql"),
-            ("musicbrainz", "CreateFunctions.sql"),
-            ("musicbrainz", "CreateMirrorOnlyFunctions.sql"),
-            ("cover_art_archive", "caa/CreateFunctions.sql"),
-            ("event_art_archive", "eaa/CreateFunctions.sql"),
-            ("musicbrainz", "CreateIndexes.sql"),
-            ("musicbrainz", "CreateMirrorIndexes.sql"),
-            ("cover_art_archive", "caa/CreateIndexes.sql"),
-            ("event_art_archive", "eaa/CreateIndexes.sql"),
-            ("statistics", "statistics/CreateIndexes.sql"),
-            ("musicbrainz", "CreateViews.sql"),
-            ("cover_art_archive", "caa/CreateViews.sql"),
-            ("event_art_archive", "eaa/CreateViews.sql"),
-            ("musicbrainz", "CreateMirrorOnlyTriggers.sql"),
-            ("musicbrainz", "ReplicationSetup.sql"),
-            ("dbmirror2", "dbmirror2/ReplicationSetup.sql"),
-        ];
-
-        for (schema, sql_script) in sql_scripts {
+        for (schema, sql_script) in SETUP_SCRIPTS {
             if self.config.should_skip_schema(schema) {
                 continue;
             }
@@ -78,28 +99,11 @@ impl<S: MbLightSettingsExt> MbLight<S> {
     }
 
     pub async fn create_tables(&mut self, local_path: &Path) -> MbLightResult<()> {
-        self.run_sql_file(local_path.join("Extensions.sql").to_str().unwrap())
-            .await?;
-        self.run_sql_file(
-            local_path
-                .join("CreateSearchConfiguration.sql")
-                .to_str()
-                .unwrap(),
-        )
-        .await?;
-        let sql_scripts = vec![
-            // types
-            ("musicbrainz", "CreateCollations.sql"),
-            ("musicbrainz", "CreateTypes.sql"),
-            // tables
-            ("musicbrainz", "CreateTables.sql"),
-            ("cover_art_archive", "caa/CreateTables.sql"),
-            ("event_art_archive", "eaa/CreateTables.sql"),
-            ("statistics", "statistics/CreateTables.sql"),
-            ("documentation", "documentation/CreateTables.sql"),
-            ("wikidocs", "wikidocs/CreateTables.sql"),
-        ];
-        for (schema, sql_script) in sql_scripts {
+        for sql_script in EXTENSION_SCRIPTS {
+            self.run_sql_file(local_path.join(sql_script).to_str().unwrap())
+                .await?;
+        }
+        for (schema, sql_script) in TABLE_SCRIPTS {
             if self.config.should_skip_schema(schema) {
                 continue;
             }
@@ -120,7 +124,8 @@ impl<S: MbLightSettingsExt> MbLight<S> {
         Ok(())
     }
 
-    pub async fn ingest_dump(&mut self) -> MbLightResult<()> {
+    /// Dump archives to import, according to the skipped schemas.
+    fn dump_archives(&self) -> Vec<&'static str> {
         let mut filenames = vec![MB_DUMP, MB_DUMP_DERIVED];
 
         if !self.config.should_skip_schema("statistics") {
@@ -133,53 +138,406 @@ impl<S: MbLightSettingsExt> MbLight<S> {
             filenames.push(EVENT_ART_ARCHIVE);
         }
 
-        let latest = self.get_latest().await?;
-        info!("Latest version: {}", latest);
+        filenames
+    }
 
+    pub async fn ingest_dump(&mut self, dump_version: &str) -> MbLightResult<()> {
+        let filenames = self.dump_archives();
         for filename in filenames {
-            let url = format!("{}/{}/{}", MUSICBRAINZ_FTP, latest, filename);
-            let tempfile = NamedTempFile::new()?;
-            let mut writer = tempfile.reopen()?;
-            self.download_with_progress(&url, &mut writer).await?;
-            let mut archive = get_archive(tempfile.path())?;
+            if ImportState::is_finished(&self.db, ImportStep::Archive(filename)).await? {
+                info!("Skipping {filename} (already imported)");
+                continue;
+            }
+
+            ImportState::start(&self.db, ImportStep::Archive(filename), dump_version).await?;
+            let url = self.fullexport_file(&format!("{dump_version}/{filename}"));
+            let sibling_url =
+                |sibling: &str| self.fullexport_file(&format!("{dump_version}/{sibling}"));
+            // Kept across runs so an interrupted download resumes where it stopped
+            let download_dir = temp_dir().join("mbpg-light").join(dump_version);
+            std::fs::create_dir_all(&download_dir)?;
+            let local_archive = download_dir.join(filename);
+
+            // A partial download is resumed, and signatures need the whole archive on disk
+            if self.config.stream_dumps()
+                && !local_archive.exists()
+                && !self.verifier.verifies_signatures()
+            {
+                let checksum = self
+                    .verifier
+                    .published_checksum(filename, &sibling_url)
+                    .await?;
+                info!("Streaming {filename} into the database");
+                match self
+                    .stream_archive(&url, filename, dump_version, checksum)
+                    .await
+                {
+                    Ok(()) => {
+                        ImportState::finish(&self.db, ImportStep::Archive(filename)).await?;
+                        continue;
+                    }
+                    // The download below copies the unfinished tables again, and verifies
+                    // the archive before it is ingested
+                    Err(
+                        e @ (MbLightError::Http(_)
+                        | MbLightError::StreamInterrupted { .. }
+                        | MbLightError::ChecksumMissmatch { .. }),
+                    ) => {
+                        warn!("Streaming {filename} failed ({e}), downloading it instead")
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
 
+            self.download_with_progress(&url, &local_archive).await?;
+            self.verifier
+                .verify(&local_archive, filename, sibling_url)
+                .await?;
             info!("Starting pg_copy for {filename}");
+            self.ingest_archive(&local_archive, dump_version).await?;
 
-            for entry in archive.entries()? {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path()?;
-                        let entry_size = entry.header().entry_size()?;
-                        let name = path.to_string_lossy().into_owned();
+            ImportState::finish(&self.db, ImportStep::Archive(filename)).await?;
+            std::fs::remove_file(&local_archive)?;
+        }
 
-                        if !name.starts_with("mbdump/") {
-                            continue;
-                        }
+        Ok(())
+    }
 
-                        let filename = name.strip_prefix("mbdump/").unwrap();
-                        let filename = filename.strip_suffix("_sanitised").unwrap_or(filename);
+    /// Ingests the dump archives stored in `dump_dir` instead of downloading them, only
+    /// `mbdump.tar.bz2` is required.
+    pub async fn ingest_dump_dir(
+        &mut self,
+        dump_dir: &Path,
+        dump_version: &str,
+    ) -> MbLightResult<()> {
+        for filename in self.dump_archives() {
+            if ImportState::is_finished(&self.db, ImportStep::Archive(filename)).await? {
+                info!("Skipping {filename} (already imported)");
+                continue;
+            }
 
-                        let (schema, table) = filename
-                            .split_once('.')
-                            .unwrap_or(("musicbrainz", filename));
+            let archive = dump_dir.join(filename);
+            if !archive.exists() {
+                if filename == MB_DUMP {
+                    return Err(MbLightError::MissingDumpArchive(archive));
+                }
+                warn!("{} not found, skipping", archive.display());
+                continue;
+            }
 
-                        if self.should_skip_table(schema, table).await? {
-                            continue;
-                        }
+            ImportState::start(&self.db, ImportStep::Archive(filename), dump_version).await?;
+            self.verifier.verify_local(dump_dir, filename).await?;
+            info!("Starting pg_copy for {}", archive.display());
+            self.ingest_archive(&archive, dump_version).await?;
+            ImportState::finish(&self.db, ImportStep::Archive(filename)).await?;
+        }
 
-                        let pb = get_progress_bar(entry_size)?;
-                        pb.set_message(table.to_string());
+        Ok(())
+    }
 
-                        self.pg_copy(entry, schema, table, pb).await?;
-                    }
-                    Err(err) => {
-                        error!("{err}");
-                        break;
-                    }
-                }
+    /// Reads the metadata entries of `mbdump.tar.bz2` in `dump_dir`.
+    pub(crate) async fn read_dump_metadata(&self, dump_dir: &Path) -> MbLightResult<DumpMetadata> {
+        let archive = dump_dir.join(MB_DUMP);
+        if !archive.exists() {
+            return Err(MbLightError::MissingDumpArchive(archive));
+        }
+
+        let bzip2_threads = self.config.bzip2_threads();
+        tokio::task::spawn_blocking(move || DumpMetadata::read(&archive, bzip2_threads))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use sha2::{Digest, Sha256};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{method, path},
+    };
+
+    use super::*;
+    use crate::{
+        musicbrainz_db::import_state::ImportStatus, settings::Settings, verify::ChecksumAlgorithm,
+    };
+
+    const VERSION: &str = "20261014-001803";
+
+    /// The tests below share `mblight_meta` and the fixture table.
+    static FIXTURE_DB: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    fn archive(entries: &[(&str, String)]) -> std::io::Result<Vec<u8>> {
+        let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, data) in entries {
+            let data = data.as_bytes();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data)?;
+        }
+        builder.into_inner()?.finish()
+    }
+
+    fn rows(count: usize) -> String {
+        (0..count)
+            .map(|id| format!("{id}\tartist {id}\n"))
+            .collect()
+    }
+
+    /// A full export mirror serving `VERSION`, with one table in `mbdump.tar.bz2`.
+    async fn fixture_mirror() -> std::io::Result<MockServer> {
+        let server = MockServer::start().await;
+        let mut sums = String::new();
+        let mut index = String::new();
+        for filename in [
+            MB_DUMP,
+            MB_DUMP_DERIVED,
+            MB_DUMP_STATS,
+            COVER_ART_ARCHIVE,
+            EVENT_ART_ARCHIVE,
+        ] {
+            let body = match filename {
+                MB_DUMP => archive(&[
+                    ("TIMESTAMP", "2026-10-14 00:18:03.465473+00\n".to_string()),
+                    ("SCHEMA_SEQUENCE", "30\n".to_string()),
+                    ("REPLICATION_SEQUENCE", "178120\n".to_string()),
+                    ("mbdump/fixture_artist", rows(1000)),
+                ])?,
+                _ => archive(&[("COPYING", "CC0".to_string())])?,
+            };
+            let digest: String = Sha256::digest(&body)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            sums.push_str(&format!("{digest}  {filename}\n"));
+            index.push_str(&format!(
+                "<a href=\"{filename}\">{filename}</a>  14-Oct-2026 02:44  {}\n",
+                body.len()
+            ));
+            Mock::given(method("GET"))
+                .and(path(format!("/{VERSION}/{filename}")))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(body))
+                .mount(&server)
+                .await;
+        }
+
+        Mock::given(path(format!("/{VERSION}/SHA256SUMS")))
+            .respond_with(ResponseTemplate::new(200).set_body_string(sums))
+            .mount(&server)
+            .await;
+        Mock::given(path(format!("/{VERSION}/")))
+            .respond_with(ResponseTemplate::new(200).set_body_string(index))
+            .mount(&server)
+            .await;
+        Mock::given(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                "<a href=\"../\">../</a>\n<a href=\"{VERSION}/\">{VERSION}/</a>  14-Oct-2026 03:47  -\n"
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(path("/LATEST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!("{VERSION}\n")))
+            .mount(&server)
+            .await;
+        Ok(server)
+    }
+
+    /// Fronts `server`, dropping the connection halfway through the body of the first `cuts`
+    /// responses for `cut_path`: a mock server always sends whole bodies.
+    async fn cutting_proxy(
+        server: &MockServer,
+        cut_path: String,
+        cuts: usize,
+    ) -> std::io::Result<String> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = format!("http://{}", listener.local_addr()?);
+        let upstream = server.uri();
+        let cuts = Arc::new(AtomicUsize::new(cuts));
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let (upstream, cut_path, cuts) = (upstream.clone(), cut_path.clone(), cuts.clone());
+                tokio::spawn(async move {
+                    let mut request = vec![0; 8192];
+                    let read = socket.read(&mut request).await?;
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let response = reqwest::get(format!("{upstream}{path}"))
+                        .await
+                        .map_err(std::io::Error::other)?;
+                    let status = response.status().as_u16();
+                    let body = response.bytes().await.map_err(std::io::Error::other)?;
+
+                    let cut = path == cut_path
+                        && cuts
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                            .is_ok();
+                    let sent = if cut { body.len() / 2 } else { body.len() };
+                    socket
+                        .write_all(
+                            format!(
+                                "HTTP/1.1 {status} Status\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                                body.len()
+                            )
+                            .as_bytes(),
+                        )
+                        .await?;
+                    socket.write_all(&body[..sent]).await?;
+                    socket.shutdown().await
+                });
             }
+        });
+        Ok(proxy)
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_ingest_dump`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_ingest_dump_from_mirror() -> MbLightResult<()> {
+        let _fixture_db = FIXTURE_DB.lock().await;
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let server = fixture_mirror().await?;
+
+        for streaming in [false, true] {
+            let mut settings = Settings::default();
+            settings.musicbrainz.fullexport_url = Some(server.uri());
+            settings.import.streaming = streaming;
+            let mut mb_light = MbLight::try_new(settings, db_url.clone()).await?;
+            sqlx::raw_sql(
+                r#"DROP SCHEMA IF EXISTS mblight_meta CASCADE;
+                   CREATE SCHEMA IF NOT EXISTS musicbrainz;
+                   DROP TABLE IF EXISTS musicbrainz.fixture_artist;
+                   CREATE TABLE musicbrainz.fixture_artist (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);"#,
+            )
+            .execute(&mb_light.db)
+            .await?;
+            ImportState::create_table(&mb_light.db).await?;
+
+            let dump_version = mb_light.get_latest().await?;
+            assert_eq!(dump_version, VERSION);
+            let dumps = mb_light.list_dumps().await?;
+            assert_eq!(dumps.len(), 1);
+            assert_eq!(dumps[0].version, VERSION);
+            assert_eq!(dumps[0].modified.as_deref(), Some("14-Oct-2026 02:44"));
+            let metadata = mb_light.fetch_dump_metadata(&dump_version).await?;
+            assert_eq!(metadata.version(), VERSION);
+            assert_eq!(metadata.schema_sequence.0, 30);
+            let mut events = mb_light.subscribe();
+            mb_light.ingest_dump(&dump_version).await?;
+            assert_eq!(
+                events.try_recv().ok(),
+                Some(crate::MbLightEvent::TableIngested {
+                    schema: "musicbrainz".to_string(),
+                    table: "fixture_artist".to_string(),
+                })
+            );
+
+            let rows: i64 = sqlx::query_scalar("SELECT count(*) FROM musicbrainz.fixture_artist")
+                .fetch_one(&mb_light.db)
+                .await?;
+            assert_eq!(rows, 1000);
+            assert!(ImportState::is_finished(&mb_light.db, ImportStep::Archive(MB_DUMP)).await?);
         }
 
         Ok(())
     }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_stream_unverified`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_stream_unverified_archive() -> MbLightResult<()> {
+        let _fixture_db = FIXTURE_DB.lock().await;
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let server = fixture_mirror().await?;
+        let mut settings = Settings::default();
+        settings.musicbrainz.fullexport_url = Some(server.uri());
+        let mb_light = MbLight::try_new(settings, db_url).await?;
+        sqlx::raw_sql(
+            r#"DROP SCHEMA IF EXISTS mblight_meta CASCADE;
+               CREATE SCHEMA IF NOT EXISTS musicbrainz;
+               DROP TABLE IF EXISTS musicbrainz.fixture_artist;
+               CREATE TABLE musicbrainz.fixture_artist (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+        ImportState::create_table(&mb_light.db).await?;
+
+        let mut events = mb_light.subscribe();
+        let url = mb_light.fullexport_file(&format!("{VERSION}/{MB_DUMP}"));
+        let checksum = Some((ChecksumAlgorithm::Sha256, "0".repeat(64)));
+        assert!(matches!(
+            mb_light
+                .stream_archive(&url, MB_DUMP, VERSION, checksum)
+                .await,
+            Err(MbLightError::ChecksumMissmatch { .. })
+        ));
+
+        // Copied but unverified: neither finished nor announced
+        let step = ImportStep::Copy {
+            schema: "musicbrainz",
+            table: "fixture_artist",
+        };
+        assert_eq!(
+            ImportState::status(&mb_light.db, step).await?,
+            Some(ImportStatus::Started)
+        );
+        assert!(events.try_recv().is_err());
+
+        sqlx::raw_sql("DROP TABLE musicbrainz.fixture_artist")
+            .execute(&mb_light.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_stream_interrupted`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_stream_interrupted_falls_back() -> MbLightResult<()> {
+        let _fixture_db = FIXTURE_DB.lock().await;
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let server = fixture_mirror().await?;
+        // The connection drops halfway through the archive, twice
+        let mirror = cutting_proxy(&server, format!("/{VERSION}/{MB_DUMP}"), 2).await?;
+        let url = format!("{mirror}/{VERSION}/{MB_DUMP}");
+
+        let mut settings = Settings::default();
+        settings.musicbrainz.fullexport_url = Some(mirror);
+        settings.import.streaming = true;
+        let mut mb_light = MbLight::try_new(settings, db_url).await?;
+        sqlx::raw_sql(
+            r#"DROP SCHEMA IF EXISTS mblight_meta CASCADE;
+               CREATE SCHEMA IF NOT EXISTS musicbrainz;
+               DROP TABLE IF EXISTS musicbrainz.fixture_artist;
+               CREATE TABLE musicbrainz.fixture_artist (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+        ImportState::create_table(&mb_light.db).await?;
+
+        assert!(matches!(
+            mb_light.stream_archive(&url, MB_DUMP, VERSION, None).await,
+            Err(MbLightError::StreamInterrupted { file, .. }) if file == MB_DUMP
+        ));
+
+        // Streamed again, interrupted again, then downloaded
+        mb_light.ingest_dump(VERSION).await?;
+        let rows: i64 = sqlx::query_scalar("SELECT count(*) FROM musicbrainz.fixture_artist")
+            .fetch_one(&mb_light.db)
+            .await?;
+        assert_eq!(rows, 1000);
+        assert!(ImportState::is_finished(&mb_light.db, ImportStep::Archive(MB_DUMP)).await?);
+
+        sqlx::raw_sql("DROP TABLE musicbrainz.fixture_artist")
+            .execute(&mb_light.db)
+            .await?;
+        Ok(())
+    }
 }
@@ -1,3 +1,7 @@
+pub(crate) mod copy_text;
+pub(crate) mod dump_metadata;
+pub(crate) mod import_state;
+pub(crate) mod ingest;
 pub(crate) mod init;
 pub(crate) mod replication;
 pub(crate) mod sql_helpers;
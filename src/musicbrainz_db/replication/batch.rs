@@ -0,0 +1,393 @@
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::{
+    MbLightError,
+    error::MbLightResult,
+    musicbrainz_db::replication::{
+        pending_data::{Operation, PendingData},
+        statement::{Statement, TableColumns, quote_ident},
+    },
+};
+
+/// Postgres accepts at most 65535 bind parameters per statement.
+const MAX_BATCH_PARAMS: usize = 65_535;
+const MAX_BATCH_ROWS: usize = 1_000;
+
+/// Consecutive pending rows of a transaction applied with a single multi-row statement.
+///
+/// Rows are only batched together when applying them at once is equivalent to applying
+/// them one by one in `seqid` order: same table, same operation, same column set and,
+/// for updates, distinct keys that are left untouched.
+#[derive(Debug)]
+pub struct Batch {
+    rows: Vec<PendingData>,
+    columns: Vec<String>,
+    keys: HashSet<String>,
+    params: usize,
+}
+
+/// Incrementally groups a stream of pending rows into [`Batch`]es.
+#[derive(Debug, Default)]
+pub struct Batcher {
+    current: Option<Batch>,
+}
+
+impl Batcher {
+    /// Adds a row to the current batch, returning the previous batch if the row
+    /// could not be added to it.
+    pub fn push(&mut self, data: PendingData) -> MbLightResult<Option<Batch>> {
+        let columns = match data.op() {
+            Operation::Insert => data.new_obj()?.keys().cloned().collect(),
+            Operation::Update => {
+                let changed = data.changed_columns()?;
+                // Nothing to apply
+                if changed.is_empty() {
+                    return Ok(None);
+                }
+                changed.into_iter().map(String::from).collect()
+            }
+            Operation::Delete => vec![],
+        };
+
+        let key = match data.op() {
+            Operation::Update => Some(key_values(&data)?),
+            _ => None,
+        };
+
+        match &mut self.current {
+            Some(batch) if batch.accepts(&data, &columns, key.as_deref()) => {
+                batch.push(data, key);
+                Ok(None)
+            }
+            _ => Ok(self.current.replace(Batch::new(data, columns, key))),
+        }
+    }
+
+    pub fn flush(&mut self) -> Option<Batch> {
+        self.current.take()
+    }
+}
+
+impl Batch {
+    /// Splits the rows of a single xid into batches, preserving their order.
+    #[cfg(test)]
+    pub fn group(rows: impl IntoIterator<Item = PendingData>) -> MbLightResult<Vec<Batch>> {
+        let mut batcher = Batcher::default();
+        let mut batches = vec![];
+        for data in rows {
+            batches.extend(batcher.push(data)?);
+        }
+        batches.extend(batcher.flush());
+        Ok(batches)
+    }
+
+    fn new(data: PendingData, columns: Vec<String>, key: Option<String>) -> Self {
+        let mut batch = Self {
+            rows: vec![],
+            params: 0,
+            columns,
+            keys: HashSet::new(),
+        };
+        batch.push(data, key);
+        batch
+    }
+
+    fn push(&mut self, data: PendingData, key: Option<String>) {
+        self.params += self.row_params(&data);
+        self.keys.extend(key);
+        self.rows.push(data);
+    }
+
+    fn row_params(&self, data: &PendingData) -> usize {
+        match data.op() {
+            Operation::Insert => self.columns.len(),
+            Operation::Update => self.columns.len() + data.sanitized_keys().len(),
+            Operation::Delete => data.sanitized_keys().len(),
+        }
+    }
+
+    fn accepts(&self, data: &PendingData, columns: &[String], key: Option<&str>) -> bool {
+        let first = &self.rows[0];
+        first.fulltable() == data.fulltable()
+            && first.op() == data.op()
+            && self.columns == columns
+            && self.rows.len() < MAX_BATCH_ROWS
+            && self.params + self.row_params(data) <= MAX_BATCH_PARAMS
+            && match data.op() {
+                Operation::Update => {
+                    // Updating a key would change which rows the following updates target
+                    let keys = first.sanitized_keys();
+                    !self.columns.iter().any(|c| keys.contains(&c.as_str()))
+                        && key.is_some_and(|key| !self.keys.contains(key))
+                }
+                _ => true,
+            }
+    }
+
+    pub fn fulltable(&self) -> &str {
+        self.rows[0].fulltable()
+    }
+
+    pub fn op(&self) -> Operation {
+        self.rows[0].op()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn rows(&self) -> &[PendingData] {
+        &self.rows
+    }
+
+    pub fn to_statement(&self, columns: &TableColumns) -> MbLightResult<Option<Statement>> {
+        let first = match self.rows.as_slice() {
+            [data] => return data.to_statement(columns),
+            [first, ..] => first,
+            [] => return Ok(None),
+        };
+
+        let (schema, table) = first.split_table_schema();
+        let fulltable = format!("{}.{}", quote_ident(schema), quote_ident(table));
+        let keys = first.sanitized_keys();
+        let mut statement = Statement::default();
+        let mut tuples = Vec::with_capacity(self.rows.len());
+
+        for data in &self.rows {
+            let mut placeholders = vec![];
+            if data.op() != Operation::Insert {
+                let old_obj = data.old_obj()?;
+                for key in &keys {
+                    let value = old_obj
+                        .get(*key)
+                        .ok_or(MbLightError::MalformedPendingData("keys"))?;
+                    placeholders.push(statement.push_param(columns, key, value)?);
+                }
+            }
+
+            if data.op() != Operation::Delete {
+                let new_obj = data.new_obj()?;
+                for column in &self.columns {
+                    let value = new_obj
+                        .get(column)
+                        .ok_or(MbLightError::MalformedPendingData("newdata"))?;
+                    placeholders.push(statement.push_param(columns, column, value)?);
+                }
+            }
+
+            tuples.push(format!("({})", placeholders.join(", ")));
+        }
+
+        let quoted_keys: Vec<String> = keys.iter().map(|k| quote_ident(k)).collect();
+        let quoted_columns: Vec<String> = self.columns.iter().map(|c| quote_ident(c)).collect();
+        let tuples = tuples.join(", ");
+
+        statement.sql = match first.op() {
+            Operation::Insert => format!(
+                "INSERT INTO {fulltable} ({}) VALUES {tuples}",
+                quoted_columns.join(", ")
+            ),
+            Operation::Delete => format!(
+                "DELETE FROM {fulltable} WHERE ({}) IN ({tuples})",
+                quoted_keys.join(", ")
+            ),
+            Operation::Update => {
+                let set_clause: Vec<String> = quoted_columns
+                    .iter()
+                    .map(|c| format!("{c} = v.{c}"))
+                    .collect();
+                let where_clause: Vec<String> = quoted_keys
+                    .iter()
+                    .map(|k| format!("t.{k} = v.{k}"))
+                    .collect();
+                format!(
+                    "UPDATE {fulltable} AS t SET {} FROM (VALUES {tuples}) AS v ({}, {}) WHERE {}",
+                    set_clause.join(", "),
+                    quoted_keys.join(", "),
+                    quoted_columns.join(", "),
+                    where_clause.join(" AND ")
+                )
+            }
+        };
+
+        Ok(Some(statement))
+    }
+}
+
+fn key_values(data: &PendingData) -> MbLightResult<String> {
+    let old_obj = data.old_obj()?;
+    let values: Vec<&Value> = data
+        .sanitized_keys()
+        .into_iter()
+        .map(|key| old_obj.get(key).unwrap_or(&Value::Null))
+        .collect();
+    Ok(serde_json::to_string(&values)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn insert(id: i64, name: &str) -> PendingData {
+        PendingData::new(
+            "musicbrainz.artist",
+            Operation::Insert,
+            None,
+            Some(json!({"id": id, "name": name})),
+        )
+    }
+
+    fn update(id: i64, old: &str, new: &str) -> PendingData {
+        PendingData::new(
+            "musicbrainz.artist",
+            Operation::Update,
+            Some(json!({"id": id, "name": old})),
+            Some(json!({"id": id, "name": new})),
+        )
+    }
+
+    fn delete(id: i64) -> PendingData {
+        PendingData::new(
+            "musicbrainz.artist",
+            Operation::Delete,
+            Some(json!({"id": id, "name": "x"})),
+            None,
+        )
+    }
+
+    fn columns() -> TableColumns {
+        TableColumns::from([("id", "int4"), ("name", "varchar")])
+    }
+
+    #[test]
+    fn test_batches_preserve_operation_order() -> MbLightResult<()> {
+        let rows = vec![
+            insert(1, "a"),
+            insert(2, "b"),
+            delete(1),
+            insert(1, "c"),
+            update(1, "c", "d"),
+            update(2, "b", "e"),
+            update(1, "d", "f"),
+        ];
+
+        let batches = Batch::group(rows)?;
+        let shape: Vec<(Operation, usize)> = batches.iter().map(|b| (b.op(), b.len())).collect();
+
+        assert_eq!(
+            shape,
+            vec![
+                (Operation::Insert, 2),
+                (Operation::Delete, 1),
+                (Operation::Insert, 1),
+                (Operation::Update, 2),
+                // Same key updated twice, must be applied after the previous batch
+                (Operation::Update, 1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_row_statements() -> MbLightResult<()> {
+        let batches = Batch::group(vec![insert(1, "a"), insert(2, "b")])?;
+        let statement = batches[0].to_statement(&columns())?.unwrap();
+        assert_eq!(
+            statement.sql,
+            r#"INSERT INTO "musicbrainz"."artist" ("id", "name") VALUES ($1::int4, $2::varchar), ($3::int4, $4::varchar)"#
+        );
+
+        let batches = Batch::group(vec![delete(1), delete(2)])?;
+        let statement = batches[0].to_statement(&columns())?.unwrap();
+        assert_eq!(
+            statement.sql,
+            r#"DELETE FROM "musicbrainz"."artist" WHERE ("id") IN (($1::int4), ($2::int4))"#
+        );
+
+        let batches = Batch::group(vec![update(1, "a", "b"), update(2, "a", "c")])?;
+        let statement = batches[0].to_statement(&columns())?.unwrap();
+        assert_eq!(
+            statement.sql,
+            r#"UPDATE "musicbrainz"."artist" AS t SET "name" = v."name" FROM (VALUES ($1::int4, $2::varchar), ($3::int4, $4::varchar)) AS v ("id", "name") WHERE t."id" = v."id""#
+        );
+        assert_eq!(
+            statement.params,
+            vec![
+                Some("1".to_string()),
+                Some("b".to_string()),
+                Some("2".to_string()),
+                Some("c".to_string())
+            ]
+        );
+        Ok(())
+    }
+
+    /// Applies the same rows one by one and batched, and checks the batched statements carry
+    /// `MAX_BATCH_ROWS` rows each.
+    ///
+    /// Drops `musicbrainz.artist`, run against a scratch database with
+    /// `MBLIGHT_BENCH_DB_URL=postgres://... cargo test -- --ignored bench_`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_batched_apply() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_BENCH_DB_URL").expect("MBLIGHT_BENCH_DB_URL");
+        let db = sqlx::PgPool::connect(&db_url).await?;
+        sqlx::raw_sql(
+            r#"CREATE SCHEMA IF NOT EXISTS musicbrainz;
+               DROP TABLE IF EXISTS musicbrainz.artist;
+               CREATE TABLE musicbrainz.artist (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);"#,
+        )
+        .execute(&db)
+        .await?;
+        let columns = TableColumns::fetch(&db, "musicbrainz", "artist").await?;
+
+        let n = 50_000;
+        let rows = || {
+            (0..n)
+                .map(|i| insert(i, "a"))
+                .chain((0..n).map(|i| update(i, "a", "b")))
+                .chain((0..n / 2).map(delete))
+        };
+        let total = rows().count();
+
+        let mut row_by_row = 0;
+        let mut tx = db.begin().await?;
+        for data in rows() {
+            if let Some(statement) = data.to_statement(&columns)? {
+                statement.query().execute(&mut *tx).await?;
+                row_by_row += 1;
+            }
+        }
+        tx.commit().await?;
+        assert_eq!(row_by_row, total);
+
+        sqlx::query("TRUNCATE musicbrainz.artist")
+            .execute(&db)
+            .await?;
+
+        let mut batched = 0;
+        let mut tx = db.begin().await?;
+        for batch in Batch::group(rows())? {
+            if let Some(statement) = batch.to_statement(&columns)? {
+                statement.query().execute(&mut *tx).await?;
+                batched += 1;
+            }
+        }
+        tx.commit().await?;
+        assert_eq!(batched, total / MAX_BATCH_ROWS);
+
+        let remaining: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM musicbrainz.artist WHERE name = 'b'")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(remaining, 25_000);
+        sqlx::query("DROP TABLE musicbrainz.artist")
+            .execute(&db)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! Change data capture: the rows applied by each replication transaction, published as one
+//! JSON line per xid.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use crate::{
+    error::MbLightResult,
+    musicbrainz_db::replication::{
+        pending_data::{Operation, PendingData},
+        replication_control::ReplicationSequence,
+    },
+    settings::CdcOutput,
+};
+
+/// A row applied by a replication transaction.
+#[derive(Debug, Serialize)]
+struct ChangeRecord {
+    table: String,
+    op: Operation,
+    keys: Map<String, Value>,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+/// The line published for a replication transaction.
+#[derive(Debug, Serialize)]
+struct XidRecord<'a> {
+    sequence: ReplicationSequence,
+    xid: i64,
+    changes: &'a [ChangeRecord],
+}
+
+/// Publishes the transactions of a replication packet to a [`CdcOutput`].
+///
+/// A transaction is published before it commits, along with the removal of its rows from
+/// `dbmirror2.pending_data`: if the commit does not happen it is applied and published again
+/// by the next run, so each change is delivered at least once.
+pub(crate) struct ChangeLog {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    sequence: ReplicationSequence,
+    changes: Vec<ChangeRecord>,
+}
+
+impl ChangeLog {
+    pub async fn open(output: &CdcOutput, sequence: ReplicationSequence) -> MbLightResult<Self> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match output {
+            CdcOutput::Stdout => Box::new(tokio::io::stdout()),
+            CdcOutput::File(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?,
+            ),
+            CdcOutput::UnixSocket(path) => Box::new(UnixStream::connect(path).await?),
+        };
+
+        Ok(Self {
+            writer,
+            sequence,
+            changes: Vec::new(),
+        })
+    }
+
+    /// Keeps `data` for the publication of its transaction. Updates changing no column are
+    /// not applied, and left out.
+    pub fn record(&mut self, data: &PendingData) -> MbLightResult<()> {
+        if data.op() == Operation::Update && data.changed_columns()?.is_empty() {
+            return Ok(());
+        }
+
+        self.changes.push(ChangeRecord {
+            table: data.fulltable().to_string(),
+            op: data.op(),
+            keys: data.primary_key()?,
+            old: data.olddata().cloned(),
+            new: data.newdata().cloned(),
+        });
+        Ok(())
+    }
+
+    /// Writes the changes recorded for `xid` as a single line.
+    pub async fn publish(&mut self, xid: i64) -> MbLightResult<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut line = serde_json::to_vec(&XidRecord {
+            sequence: self.sequence,
+            xid,
+            changes: &self.changes,
+        })?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+        self.changes.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_xids() -> MbLightResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("changes.jsonl");
+        let output = CdcOutput::File(path.clone());
+
+        let mut log = ChangeLog::open(&output, ReplicationSequence(101)).await?;
+        log.record(&PendingData::new(
+            "musicbrainz.artist",
+            Operation::Insert,
+            None,
+            Some(serde_json::json!({"id": 1, "name": "a"})),
+        ))?;
+        log.record(&PendingData::new(
+            "musicbrainz.artist",
+            Operation::Update,
+            Some(serde_json::json!({"id": 1, "name": "a"})),
+            Some(serde_json::json!({"id": 1, "name": "a"})),
+        ))?;
+        log.publish(7).await?;
+        // Nothing left to publish
+        log.publish(7).await?;
+
+        let mut log = ChangeLog::open(&output, ReplicationSequence(102)).await?;
+        log.record(&PendingData::new(
+            "musicbrainz.artist",
+            Operation::Delete,
+            Some(serde_json::json!({"id": 1, "name": "a"})),
+            None,
+        ))?;
+        log.publish(8).await?;
+
+        let lines: Vec<Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "sequence": 101,
+                    "xid": 7,
+                    "changes": [{
+                        "table": "musicbrainz.artist",
+                        "op": "insert",
+                        "keys": {"id": 1},
+                        "old": null,
+                        "new": {"id": 1, "name": "a"},
+                    }],
+                }),
+                serde_json::json!({
+                    "sequence": 102,
+                    "xid": 8,
+                    "changes": [{
+                        "table": "musicbrainz.artist",
+                        "op": "delete",
+                        "keys": {"id": 1},
+                        "old": {"id": 1, "name": "a"},
+                        "new": null,
+                    }],
+                }),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_publish_applied`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_publish_applied_xids() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("changes.jsonl");
+
+        let mut settings = crate::settings::Settings::default();
+        settings.cdc.output = Some(path.display().to_string());
+        let mb_light = crate::MbLight::try_new(settings, db_url).await?;
+        sqlx::raw_sql(
+            r#"DROP TABLE IF EXISTS musicbrainz.fixture_cdc;
+               CREATE TABLE musicbrainz.fixture_cdc (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);
+               TRUNCATE dbmirror2.pending_data, dbmirror2.pending_keys;
+               INSERT INTO dbmirror2.pending_keys VALUES ('musicbrainz.fixture_cdc', '{id}');
+               INSERT INTO dbmirror2.pending_data (tablename, op, xid, olddata, newdata) VALUES
+                   ('musicbrainz.fixture_cdc', 'i', 1, NULL, '{"id": 1, "name": "a"}'),
+                   ('musicbrainz.fixture_cdc', 'i', 1, NULL, '{"id": 2, "name": "b"}'),
+                   ('musicbrainz.fixture_cdc', 'u', 2, '{"id": 1, "name": "a"}', '{"id": 1, "name": "c"}'),
+                   ('musicbrainz.fixture_cdc', 'i', 3, NULL, '{"id": 2, "name": "d"}');"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+
+        // The last xid conflicts with the first one, it is neither applied nor published
+        assert!(
+            mb_light
+                .apply_pending_data(ReplicationSequence(12))
+                .await
+                .is_err()
+        );
+        let lines: Vec<Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["sequence"], 12);
+        assert_eq!(lines[0]["changes"].as_array().map(Vec::len), Some(2));
+        assert_eq!(lines[1]["xid"], 2);
+        assert_eq!(lines[1]["changes"][0]["new"]["name"], "c");
+        let pending: Vec<i64> = sqlx::query_scalar("SELECT xid FROM dbmirror2.pending_data")
+            .fetch_all(&mb_light.db)
+            .await?;
+        assert_eq!(pending, vec![3]);
+
+        sqlx::raw_sql(
+            r#"TRUNCATE dbmirror2.pending_data, dbmirror2.pending_keys;
+               DROP TABLE musicbrainz.fixture_cdc;"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+        Ok(())
+    }
+}
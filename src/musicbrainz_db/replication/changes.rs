@@ -0,0 +1,71 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    error::MbLightResult,
+    musicbrainz_db::replication::pending_data::{Operation, PendingData},
+};
+
+/// Number of replicated rows per operation for a single table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TableChanges {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
+}
+
+impl TableChanges {
+    pub(crate) fn record(&mut self, op: Operation) {
+        match op {
+            Operation::Insert => self.inserts += 1,
+            Operation::Update => self.updates += 1,
+            Operation::Delete => self.deletes += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.inserts + self.updates + self.deletes
+    }
+}
+
+impl fmt::Display for TableChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} inserts, {} updates, {} deletes",
+            self.inserts, self.updates, self.deletes
+        )
+    }
+}
+
+/// Replicated rows per `schema.table`.
+pub type ReplicationChanges = BTreeMap<String, TableChanges>;
+
+/// Rows of a single table applied by a replication packet, with their primary keys in
+/// application order: both the old and the new one for an update changing it.
+///
+/// Keys are only collected when the packet started with an [`crate::MbLight::subscribe`]
+/// receiver, `keys` is empty otherwise.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TableRows {
+    pub changes: TableChanges,
+    pub keys: Vec<Map<String, Value>>,
+}
+
+impl TableRows {
+    pub(crate) fn record(&mut self, data: &PendingData, with_keys: bool) -> MbLightResult<()> {
+        self.changes.record(data.op());
+        if with_keys {
+            self.keys.push(data.primary_key()?);
+            if let Some(new_key) = data.changed_primary_key()? {
+                self.keys.push(new_key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applied rows per `schema.table`.
+pub type AppliedChanges = BTreeMap<String, TableRows>;
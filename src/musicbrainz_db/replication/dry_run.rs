@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    MbLight,
+    error::MbLightResult,
+    musicbrainz_db::{
+        copy_text::{decode_field, split_row},
+        dump_metadata::DumpMetadata,
+        replication::{
+            changes::ReplicationChanges,
+            log_timestamp,
+            pending_data::PendingData,
+            replication_control::{ReplicationControl, ReplicationSequence, SchemaTransition},
+            statement::ColumnCache,
+        },
+    },
+    progress::get_progress_bar,
+    settings::MbLightSettingsExt,
+    tar_helper::get_archive,
+};
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Fetch the next replication packet and write the statements it would execute
+    /// to `output`, without loading it into `dbmirror2` nor modifying the mirror.
+    pub async fn dry_run(&self, output: &mut impl Write) -> MbLightResult<ReplicationChanges> {
+        let mut replication_control = ReplicationControl::get(&self.db).await?;
+        let next_replication_sequence = replication_control.next_replication_sequence()?;
+        let packet = self.fetch_next_packet(&replication_control).await?;
+
+        let metadata = DumpMetadata::read(packet.path(), self.config.bzip2_threads())?;
+        log_timestamp(&metadata);
+        if let SchemaTransition::Upgrade(schema_sequence) =
+            replication_control.advance(&metadata)?
+        {
+            warn!("Replication packet requires a schema update to version {schema_sequence}");
+            writeln!(output, "-- requires schema update to {schema_sequence}")?;
+            for statement in self.schema_change_statements(schema_sequence).await? {
+                writeln!(output, "{statement};")?;
+            }
+        }
+
+        // pending_keys are needed to build the pending_data statements, whatever the archive order
+        let mut keys = HashMap::new();
+        for entry in get_archive(packet.path(), self.config.bzip2_threads())?.entries()? {
+            let entry = entry?;
+            if entry.path()?.file_name().and_then(|f| f.to_str()) == Some("pending_keys") {
+                keys = read_pending_keys(entry)?;
+            }
+        }
+
+        let mut changes = ReplicationChanges::new();
+        let mut columns = ColumnCache::default();
+        for entry in get_archive(packet.path(), self.config.bzip2_threads())?.entries()? {
+            let entry = entry?;
+            if entry.path()?.file_name().and_then(|f| f.to_str()) != Some("pending_data") {
+                continue;
+            }
+
+            let pb = get_progress_bar(entry.size())?;
+            pb.set_message("pending_data");
+            let mut current_xid = None;
+            for row in BufReader::new(pb.wrap_read(entry)).split(b'\n') {
+                let row = row?;
+                if row.is_empty() || row == b"\\." {
+                    continue;
+                }
+
+                let data = PendingData::from_copy_row(&row, &keys)?;
+                if self.should_skip_fulltable(data.fulltable()) {
+                    continue;
+                }
+
+                if current_xid != Some(data.xid) {
+                    writeln!(output, "-- xid {}", data.xid)?;
+                    current_xid = Some(data.xid);
+                }
+
+                let table_columns = columns.get(&self.db, data.fulltable()).await?;
+                if let Some(query) = data.to_sql_inline(table_columns)? {
+                    writeln!(output, "{query}")?;
+                }
+
+                changes
+                    .entry(data.fulltable().to_string())
+                    .or_default()
+                    .record(data.op());
+            }
+            pb.finish_with_message("Dry run completed");
+        }
+
+        write_summary(output, next_replication_sequence, &changes)?;
+        output.flush()?;
+        info!("Replication packet {next_replication_sequence} would apply:");
+        for (table, table_changes) in &changes {
+            info!("  {table}: {table_changes}");
+        }
+
+        Ok(changes)
+    }
+}
+
+/// Appends the per-table change counts to the statements, as SQL comments.
+fn write_summary(
+    output: &mut impl Write,
+    sequence: ReplicationSequence,
+    changes: &ReplicationChanges,
+) -> MbLightResult<()> {
+    writeln!(output, "-- replication packet {sequence} would apply:")?;
+    for (table, table_changes) in changes {
+        writeln!(output, "-- {table}: {table_changes}")?;
+    }
+    Ok(())
+}
+
+/// Reads a `dbmirror2.pending_keys` COPY text stream into a table to key columns map.
+fn read_pending_keys(entry: impl Read) -> MbLightResult<HashMap<String, Vec<String>>> {
+    let mut keys = HashMap::new();
+    for row in BufReader::new(entry).split(b'\n') {
+        let row = row?;
+        let mut fields = split_row(&row).map(decode_field);
+        if let (Some(Some(table)), Some(Some(columns))) = (fields.next(), fields.next()) {
+            let columns = columns
+                .trim_matches(['{', '}'])
+                .split(',')
+                .map(|column| column.trim_matches('"').to_string())
+                .collect();
+            keys.insert(table, columns);
+        }
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pending_rows() -> MbLightResult<()> {
+        let keys = read_pending_keys(
+            "musicbrainz.artist\t{id}\nmusicbrainz.l_artist_url\t{entity0,entity1}\n".as_bytes(),
+        )?;
+        assert_eq!(keys["musicbrainz.l_artist_url"], vec!["entity0", "entity1"]);
+
+        let row = br#"42	musicbrainz.artist	u	1234	{"id":1,"name":"a\\tb"}	{"id":1,"name":"c"}	1"#;
+        let data = PendingData::from_copy_row(row, &keys)?;
+
+        assert_eq!(data.fulltable(), "musicbrainz.artist");
+        assert_eq!(data.xid, 1234);
+        assert_eq!(data.changed_columns()?, vec!["name"]);
+        assert_eq!(data.old_obj()?["name"], "a\tb");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_summary() -> MbLightResult<()> {
+        let mut changes = ReplicationChanges::new();
+        changes.insert(
+            "musicbrainz.artist".to_string(),
+            crate::TableChanges {
+                inserts: 2,
+                updates: 1,
+                deletes: 0,
+            },
+        );
+        changes.insert(
+            "musicbrainz.release".to_string(),
+            crate::TableChanges {
+                inserts: 0,
+                updates: 0,
+                deletes: 3,
+            },
+        );
+
+        let mut output = Vec::new();
+        write_summary(&mut output, ReplicationSequence(101), &changes)?;
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            "-- replication packet 101 would apply:\n\
+             -- musicbrainz.artist: 2 inserts, 1 updates, 0 deletes\n\
+             -- musicbrainz.release: 0 inserts, 0 updates, 3 deletes\n"
+        );
+        Ok(())
+    }
+}
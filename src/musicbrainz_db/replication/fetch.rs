@@ -0,0 +1,61 @@
+use futures_util::{StreamExt, stream};
+use tracing::info;
+
+use crate::{
+    MbLight, MbLightError, error::MbLightResult,
+    musicbrainz_db::replication::replication_control::ReplicationControl,
+    settings::MbLightSettingsExt,
+};
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Download replication packets ahead into the packet cache, from `from` (defaults to
+    /// the next packet to apply) up to `until`, or the latest published packet when `None`.
+    ///
+    /// Returns the last sequence available in the cache, if any.
+    pub async fn fetch_packets(
+        &self,
+        from: Option<i32>,
+        until: Option<i32>,
+    ) -> MbLightResult<Option<i32>> {
+        let Some(cache_dir) = self.config.packet_cache_dir() else {
+            return Err(MbLightError::MissingPacketCache);
+        };
+
+        let from = match from {
+            Some(from) => from,
+            None => {
+                ReplicationControl::get(&self.db)
+                    .await?
+                    .next_replication_sequence()?
+                    .0
+            }
+        };
+
+        info!(
+            "Fetching replication packets {from} to {} into {}",
+            until.map_or("latest".to_string(), |until| until.to_string()),
+            cache_dir.display()
+        );
+
+        let mut packets = stream::iter(from..=until.unwrap_or(i32::MAX))
+            .map(|sequence| async move { (sequence, self.packet_source.fetch(sequence).await) })
+            .buffered(self.config.fetch_concurrency().max(1));
+
+        let mut last = None;
+        while let Some((sequence, packet)) = packets.next().await {
+            match packet {
+                Ok(_) => last = Some(sequence),
+                // Downloads still in flight are past the latest packet and dropped with the stream
+                Err(MbLightError::NotFound) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        match last {
+            Some(last) => info!("Replication packets cached up to {last}"),
+            None => info!("No new replication packet to fetch"),
+        }
+
+        Ok(last)
+    }
+}
@@ -1,35 +1,63 @@
 use std::io::Read;
 
 use crate::{
-    MbLight,
+    MbLight, MbLightEvent,
     error::{MbLightError, MbLightResult},
+    musicbrainz_db::copy_text::CopyFilter,
+    musicbrainz_db::dump_metadata::DumpMetadata,
     musicbrainz_db::replication::{
-        pending_data::PendingData, replication_control::ReplicationControl,
+        batch::{Batch, Batcher},
+        cdc::ChangeLog,
+        changes::AppliedChanges,
+        notify::XidNotifier,
+        pending_data::PendingData,
+        replication_control::{ReplicationControl, ReplicationSequence, SchemaTransition},
+        statement::ColumnCache,
     },
+    packet_source::ReplicationPacket,
     progress::get_progress_bar,
     settings::MbLightSettingsExt,
     tar_helper::get_archive,
 };
-use itertools::Itertools;
-use sqlx::types::chrono::{DateTime, Utc};
-use tempfile::NamedTempFile;
+use futures_util::TryStreamExt;
+use indicatif::ProgressBar;
+use sqlx::{
+    Postgres, Transaction,
+    types::chrono::{DateTime, Utc},
+};
 use tracing::{debug, error, info};
 
+mod batch;
+mod cdc;
+pub(crate) mod changes;
+mod dry_run;
+mod fetch;
+mod notify;
 mod pending_data;
+mod post_sync;
 pub(crate) mod replication_control;
+pub(crate) mod schema_change;
+mod statement;
 
 impl<S: MbLightSettingsExt> MbLight<S> {
     pub async fn apply_pending_replication(&self) -> Result<(), MbLightError> {
-        let remains = PendingData::all(&self.db).await?;
-        if !remains.is_empty() {
-            let replication_control = ReplicationControl::get(&self.db).await?;
+        if PendingData::count(&self.db).await? > 0 {
+            let mut replication_control = ReplicationControl::get(&self.db).await?;
             info!("Applying unfinished replication packet");
-            self.apply_pending_data().await?;
+            let sequence = replication_control.advance_pending()?;
+            let changes = self.apply_pending_data(sequence).await?;
             info!("Replication finished");
             replication_control.update(&self.db).await?;
+            self.notify_packet(sequence, None, &changes).await;
+            self.emit(MbLightEvent::PacketApplied {
+                sequence,
+                timestamp: None,
+                changes,
+            });
+            self.packet_applied(sequence).await;
         }
 
-        let replication_control = ReplicationControl::get(&self.db).await?;
+        let mut replication_control = ReplicationControl::get(&self.db).await?;
 
         let next_replication_sequence = replication_control.next_replication_sequence()?;
         let last_replication_date = replication_control
@@ -39,36 +67,66 @@ impl<S: MbLightSettingsExt> MbLight<S> {
         info!(
             "Starting new replication process, last replication occured on {last_replication_date}",
         );
-        let tmpfile = NamedTempFile::new()?;
-        {
-            let mut writer = tmpfile.reopen()?;
-            let packet_url = replication_control.next_replication_packet_url(
-                self.config.musicbrainz_url(),
-                self.config.musicbrainz_token(),
-            )?;
-
-            self.download_with_progress(&packet_url, &mut writer)
-                .await?;
-        }
+        let packet = self.fetch_next_packet(&replication_control).await?;
 
         info!(
-            "Replication packet {} downloaded, processing...",
+            "Replication packet {} fetched, processing...",
             next_replication_sequence
         );
-        let mut archive = get_archive(tmpfile.path())?;
+        let metadata = DumpMetadata::read(packet.path(), self.config.bzip2_threads())?;
+        log_timestamp(&metadata);
+        let transition = replication_control.advance(&metadata)?;
+        self.emit(MbLightEvent::PacketStarted {
+            sequence: metadata.replication_sequence,
+            timestamp: metadata.timestamp,
+        });
+        if let SchemaTransition::Upgrade(schema_sequence) = transition {
+            info!("Updating schema to version {}", schema_sequence);
+            self.migrate_schema(schema_sequence).await?;
+            self.emit(MbLightEvent::SchemaUpgraded { schema_sequence });
+        }
 
+        let mut archive = get_archive(packet.path(), self.config.bzip2_threads())?;
         for entry in archive.entries()? {
-            self.process_replication_entry(&replication_control, entry, next_replication_sequence)
-                .await?;
+            self.process_replication_entry(entry).await?;
         }
 
-        self.apply_pending_data().await?;
+        let changes = self
+            .apply_pending_data(metadata.replication_sequence)
+            .await?;
         info!("replication finished");
         replication_control.update(&self.db).await?;
+        self.notify_packet(
+            metadata.replication_sequence,
+            Some(metadata.timestamp),
+            &changes,
+        )
+        .await;
+        self.emit(MbLightEvent::PacketApplied {
+            sequence: metadata.replication_sequence,
+            timestamp: Some(metadata.timestamp),
+            changes,
+        });
+        self.packet_applied(metadata.replication_sequence).await;
 
         Ok(())
     }
 
+    /// Tells the packet source `sequence` is applied, a failure there is only logged.
+    async fn packet_applied(&self, sequence: ReplicationSequence) {
+        if let Err(err) = self.packet_source.applied(sequence.0).await {
+            error!("Failed to clean up packets up to {sequence}: {err}");
+        }
+    }
+
+    async fn fetch_next_packet(
+        &self,
+        replication_control: &ReplicationControl,
+    ) -> MbLightResult<ReplicationPacket> {
+        let sequence = replication_control.next_replication_sequence()?;
+        self.packet_source.fetch(sequence.0).await
+    }
+
     pub async fn drop_tablecheck(&self) -> MbLightResult<()> {
         sqlx::query(
             "ALTER TABLE dbmirror2.pending_data DROP CONSTRAINT IF EXISTS tablename_exists;",
@@ -80,57 +138,28 @@ impl<S: MbLightSettingsExt> MbLight<S> {
 
     async fn process_replication_entry(
         &self,
-        replication_control: &ReplicationControl,
         entry: Result<tar::Entry<'_, impl Read>, std::io::Error>,
-        next_replication_sequence: i32,
     ) -> Result<(), MbLightError> {
         match entry {
-            Ok(mut entry) => {
+            Ok(entry) => {
                 let path = entry.path()?;
                 let filename = path.as_ref().file_name().and_then(|f| f.to_str());
                 debug!("processing {}", filename.unwrap_or("unknown"));
                 match filename {
                     Some("pending_data") => {
                         let pb = get_progress_bar(entry.size())?;
-                        self.pg_copy(entry, "dbmirror2", "pending_data", pb).await?;
+                        // tablename is the second pending_data column
+                        let rows = CopyFilter::new(pb.wrap_read(entry), 1, |fulltable: &str| {
+                            !self.should_skip_fulltable(fulltable)
+                        });
+                        self.pg_copy(rows, "dbmirror2", "pending_data", pb).await?;
                     }
                     Some("pending_keys") => {
                         let pb = get_progress_bar(entry.size())?;
-                        self.pg_copy(entry, "dbmirror2", "pending_keys", pb).await?;
-                    }
-                    Some("REPLICATION_SEQUENCE") => {
-                        let mut replication_sequence = String::new();
-                        let _ = entry.read_to_string(&mut replication_sequence);
-                        let replication_sequence = replication_sequence.trim();
-                        let replication_sequence = replication_sequence.parse::<i32>()?;
-                        if replication_sequence != next_replication_sequence {
-                            return Err(MbLightError::SequenceMissmatch {
-                                expected: next_replication_sequence,
-                                got: replication_sequence,
-                            });
-                        }
-                    }
-                    Some("SCHEMA_SEQUENCE") => {
-                        let mut schema_sequence = String::new();
-                        let _ = entry.read_to_string(&mut schema_sequence)?;
-                        let schema_sequence = schema_sequence.trim();
-                        let schema_sequence = schema_sequence.parse::<i32>()?;
-                        if replication_control.is_next(schema_sequence)? {
-                            info!("Downloading schema update");
-                            let path = self.download_schema_update(schema_sequence).await?;
-                            info!("Updating schema to version {}", schema_sequence);
-                            self.run_sql_file(path).await?;
-                        } else if !replication_control.schema_sequence_match(schema_sequence) {
-                            return Err(MbLightError::SchemaMissmatch {
-                                expected: replication_control
-                                    .current_replication_sequence
-                                    .unwrap_or_default(),
-                                got: schema_sequence,
-                            });
-                        }
-                    }
-                    Some("TIMESTAMP") => {
-                        extract_timestamp(entry)?;
+                        let rows = CopyFilter::new(pb.wrap_read(entry), 0, |fulltable: &str| {
+                            !self.should_skip_fulltable(fulltable)
+                        });
+                        self.pg_copy(rows, "dbmirror2", "pending_keys", pb).await?;
                     }
                     _ => {}
                 }
@@ -143,45 +172,154 @@ impl<S: MbLightSettingsExt> MbLight<S> {
         Ok(())
     }
 
-    async fn apply_pending_data(&self) -> MbLightResult<()> {
-        let mut pending_data = PendingData::all(&self.db).await?;
-        pending_data.retain(|p| {
-            let (schema, table) = p.split_table_schema();
-            !self.config.should_skip_schema(schema) && !self.config.should_skip_table(table)
-        });
-        info!("Processing {} pending data ...", pending_data.len());
-        let pb = get_progress_bar(pending_data.len() as u64)?;
-        let chunked_data = pending_data.into_iter().chunk_by(|data| data.xid);
-
-        for (xid, group) in chunked_data.into_iter() {
-            let mut tx = self.db.begin().await?;
-            for data in group {
-                match data.to_sql_inline() {
-                    Ok(Some(query)) => {
-                        sqlx::query(&query).execute(&mut *tx).await?;
-                    }
-                    Err(e) => {
-                        error!("Failed to process pending data: {data:?}");
-                        pb.finish_with_message("Failed");
-                        return Err(e);
+    /// Whether rows of a `schema.table` replicated table are filtered out by the config.
+    fn should_skip_fulltable(&self, fulltable: &str) -> bool {
+        let (schema, table) = fulltable
+            .split_once('.')
+            .unwrap_or(("musicbrainz", fulltable));
+        self.config.should_skip_schema(schema) || self.config.should_skip_table(table)
+    }
+
+    /// Applies the rows of `dbmirror2.pending_data` loaded from the packet `sequence`, one
+    /// transaction per xid, and returns them per table.
+    async fn apply_pending_data(
+        &self,
+        sequence: ReplicationSequence,
+    ) -> MbLightResult<AppliedChanges> {
+        let count = PendingData::count(&self.db).await?;
+        info!("Processing {} pending data ...", count);
+        let pb = get_progress_bar(count as u64)?;
+        let mut columns = ColumnCache::default();
+        let mut batcher = Batcher::default();
+        let mut current: Option<(i64, Transaction<'_, Postgres>)> = None;
+        let mut rows = PendingData::stream(&self.db);
+        let mut changes = AppliedChanges::new();
+        // Keys only matter to the receivers of `PacketApplied`
+        let with_keys = self.events.receiver_count() > 0;
+        let mut change_log = match self.config.cdc_output() {
+            Some(output) => Some(ChangeLog::open(&output, sequence).await?),
+            None => None,
+        };
+        let mut xid_notifier = self
+            .config
+            .notify_xid_channel()
+            .map(|channel| XidNotifier::new(channel, sequence));
+
+        while let Some(data) = rows.try_next().await? {
+            pb.inc(1);
+            if self.should_skip_fulltable(data.fulltable()) {
+                continue;
+            }
+
+            if current.as_ref().is_none_or(|(xid, _)| *xid != data.xid) {
+                if let Some((xid, mut tx)) = current.take() {
+                    if let Some(batch) = batcher.flush() {
+                        self.apply_batch(&mut tx, &mut columns, batch, &pb).await?;
                     }
-                    Ok(None) => {}
+                    Self::commit_xid(tx, xid, &pb, change_log.as_mut(), xid_notifier.as_mut())
+                        .await?;
                 }
-                pb.inc(1);
+                current = Some((data.xid, self.db.begin().await?));
             }
-            pb.set_message(format!("Removing pending data for xid {}", xid));
-            let tx = PendingData::remove_by_xid(tx, xid).await?;
-            pb.set_message("Committing ...");
-            tx.commit().await?;
-            pb.finish_with_message("Done");
+
+            changes
+                .entry(data.fulltable().to_string())
+                .or_default()
+                .record(&data, with_keys)?;
+            if let Some(change_log) = change_log.as_mut() {
+                change_log.record(&data)?;
+            }
+            if let Some(xid_notifier) = xid_notifier.as_mut() {
+                xid_notifier.record(&data);
+            }
+
+            if let Some(batch) = batcher.push(data)?
+                && let Some((_, tx)) = current.as_mut()
+            {
+                self.apply_batch(tx, &mut columns, batch, &pb).await?;
+            }
+        }
+
+        if let Some((xid, mut tx)) = current.take() {
+            if let Some(batch) = batcher.flush() {
+                self.apply_batch(&mut tx, &mut columns, batch, &pb).await?;
+            }
+            Self::commit_xid(tx, xid, &pb, change_log.as_mut(), xid_notifier.as_mut()).await?;
         }
+
         self.truncate_pending_data().await?;
         pb.finish_with_message("Replication completed");
+        Ok(changes)
+    }
+
+    async fn apply_batch(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        columns: &mut ColumnCache,
+        batch: Batch,
+        pb: &ProgressBar,
+    ) -> MbLightResult<()> {
+        let table_columns = columns.get(&self.db, batch.fulltable()).await?;
+        match batch.to_statement(table_columns) {
+            Ok(Some(statement)) => {
+                if let Err(e) = statement.query().execute(&mut **tx).await {
+                    error!(
+                        "Failed to apply {} {} on {}: {}",
+                        batch.len(),
+                        batch.op(),
+                        batch.fulltable(),
+                        e
+                    );
+                    for data in batch.rows() {
+                        if let Some(query) = data.to_sql_inline(table_columns)? {
+                            debug!("{query}");
+                        }
+                    }
+                    pb.finish_with_message("Failed");
+                    return Err(e.into());
+                }
+            }
+            Err(e) => {
+                error!("Failed to process pending data: {batch:?}");
+                pb.finish_with_message("Failed");
+                return Err(e);
+            }
+            Ok(None) => {}
+        }
+
+        Ok(())
+    }
+
+    async fn commit_xid(
+        tx: Transaction<'_, Postgres>,
+        xid: i64,
+        pb: &ProgressBar,
+        change_log: Option<&mut ChangeLog>,
+        xid_notifier: Option<&mut XidNotifier>,
+    ) -> MbLightResult<()> {
+        pb.set_message(format!("Removing pending data for xid {}", xid));
+        let mut tx = PendingData::remove_by_xid(tx, xid).await?;
+        if let Some(xid_notifier) = xid_notifier {
+            xid_notifier.notify(&mut *tx, xid).await?;
+        }
+        if let Some(change_log) = change_log {
+            // Published first, a failed commit publishes the xid again on the next run
+            change_log.publish(xid).await?;
+        }
+        pb.set_message("Committing ...");
+        tx.commit().await?;
         Ok(())
     }
 }
 
-fn extract_timestamp(mut entry: impl std::io::Read) -> MbLightResult<()> {
+pub(crate) fn read_sequence(mut entry: impl std::io::Read) -> MbLightResult<i32> {
+    let mut sequence = String::new();
+    entry.read_to_string(&mut sequence)?;
+    Ok(sequence.trim().parse::<i32>()?)
+}
+
+/// Parses a `TIMESTAMP` archive entry, as written by the MusicBrainz export scripts.
+pub(crate) fn read_timestamp(mut entry: impl std::io::Read) -> MbLightResult<DateTime<Utc>> {
     let mut date_str = String::new();
     entry.read_to_string(&mut date_str)?;
     let date_str = date_str.trim();
@@ -194,8 +332,10 @@ fn extract_timestamp(mut entry: impl std::io::Read) -> MbLightResult<()> {
         date_str.to_string()
     };
 
-    let date = DateTime::parse_from_str(&date_str, "%Y-%m-%d %H:%M:%S%.f%:z")?.with_timezone(&Utc);
-    let date = date.format("%Y-%m-%d %H:%M:%S");
+    Ok(DateTime::parse_from_str(&date_str, "%Y-%m-%d %H:%M:%S%.f%:z")?.with_timezone(&Utc))
+}
+
+fn log_timestamp(metadata: &DumpMetadata) {
+    let date = metadata.timestamp.format("%Y-%m-%d %H:%M:%S");
     info!("Replication packet emitted at: {date}");
-    Ok(())
 }
@@ -0,0 +1,278 @@
+//! `NOTIFY` of the applied packets and transactions, for listeners on the mirror database.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+use sqlx::{
+    PgExecutor,
+    types::chrono::{DateTime, Utc},
+};
+use tracing::error;
+
+use crate::{
+    MbLight, TableChanges,
+    error::MbLightResult,
+    musicbrainz_db::replication::{
+        changes::AppliedChanges, pending_data::PendingData,
+        replication_control::ReplicationSequence,
+    },
+    settings::MbLightSettingsExt,
+};
+
+/// Postgres rejects notification payloads of 8000 bytes or more.
+const MAX_PAYLOAD: usize = 7999;
+
+/// Sent once a packet is applied and recorded in `replication_control`.
+#[derive(Debug, Serialize)]
+struct PacketNotification<'a> {
+    sequence: ReplicationSequence,
+    timestamp: Option<DateTime<Utc>>,
+    tables: BTreeMap<&'a str, TableChanges>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+/// Sent with each replicated transaction, on commit.
+#[derive(Debug, Serialize)]
+struct XidNotification<'a> {
+    sequence: ReplicationSequence,
+    xid: i64,
+    tables: Vec<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Notifies [`MbLightSettingsExt::notify_channel`] that the packet `sequence` was applied.
+    /// The packet is already recorded, a failed notification is only logged.
+    pub(crate) async fn notify_packet(
+        &self,
+        sequence: ReplicationSequence,
+        timestamp: Option<DateTime<Utc>>,
+        changes: &AppliedChanges,
+    ) {
+        let Some(channel) = self.config.notify_channel() else {
+            return;
+        };
+
+        let notified = match packet_payload(sequence, timestamp, changes) {
+            Ok(payload) => notify(&self.db, channel, &payload).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = notified {
+            error!("Failed to notify {channel} of packet {sequence}: {err}");
+        }
+    }
+}
+
+/// The packet payload, without its tables when they do not fit.
+fn packet_payload(
+    sequence: ReplicationSequence,
+    timestamp: Option<DateTime<Utc>>,
+    changes: &AppliedChanges,
+) -> MbLightResult<String> {
+    let mut notification = PacketNotification {
+        sequence,
+        timestamp,
+        tables: changes
+            .iter()
+            .map(|(table, rows)| (table.as_str(), rows.changes))
+            .collect(),
+        truncated: false,
+    };
+
+    let payload = serde_json::to_string(&notification)?;
+    if payload.len() <= MAX_PAYLOAD {
+        return Ok(payload);
+    }
+
+    notification.tables.clear();
+    notification.truncated = true;
+    Ok(serde_json::to_string(&notification)?)
+}
+
+/// The transaction payload, without its tables when they do not fit.
+fn xid_payload(
+    sequence: ReplicationSequence,
+    xid: i64,
+    tables: &BTreeSet<String>,
+) -> MbLightResult<String> {
+    let mut notification = XidNotification {
+        sequence,
+        xid,
+        tables: tables.iter().map(String::as_str).collect(),
+        truncated: false,
+    };
+
+    let payload = serde_json::to_string(&notification)?;
+    if payload.len() <= MAX_PAYLOAD {
+        return Ok(payload);
+    }
+
+    notification.tables.clear();
+    notification.truncated = true;
+    Ok(serde_json::to_string(&notification)?)
+}
+
+async fn notify(executor: impl PgExecutor<'_>, channel: &str, payload: &str) -> MbLightResult<()> {
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(channel)
+        .bind(payload)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Collects the tables of a transaction to notify them from within it, so the notification
+/// is only delivered if it commits.
+pub(crate) struct XidNotifier {
+    channel: String,
+    sequence: ReplicationSequence,
+    tables: BTreeSet<String>,
+}
+
+impl XidNotifier {
+    pub fn new(channel: &str, sequence: ReplicationSequence) -> Self {
+        Self {
+            channel: channel.to_string(),
+            sequence,
+            tables: BTreeSet::new(),
+        }
+    }
+
+    pub fn record(&mut self, data: &PendingData) {
+        if !self.tables.contains(data.fulltable()) {
+            self.tables.insert(data.fulltable().to_string());
+        }
+    }
+
+    pub async fn notify(&mut self, executor: impl PgExecutor<'_>, xid: i64) -> MbLightResult<()> {
+        let payload = xid_payload(self.sequence, xid, &self.tables)?;
+        self.tables.clear();
+        notify(executor, &self.channel, &payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TableRows;
+
+    #[test]
+    fn test_packet_payload() -> MbLightResult<()> {
+        let mut changes = AppliedChanges::new();
+        changes.insert(
+            "musicbrainz.artist".to_string(),
+            TableRows {
+                changes: TableChanges {
+                    inserts: 2,
+                    updates: 1,
+                    deletes: 0,
+                },
+                keys: vec![],
+            },
+        );
+
+        let payload = packet_payload(ReplicationSequence(101), None, &changes)?;
+        assert_eq!(
+            payload,
+            r#"{"sequence":101,"timestamp":null,"tables":{"musicbrainz.artist":{"inserts":2,"updates":1,"deletes":0}}}"#
+        );
+
+        for i in 0..200 {
+            changes.insert(format!("musicbrainz.table_{i}"), TableRows::default());
+        }
+        let payload = packet_payload(ReplicationSequence(101), None, &changes)?;
+        assert_eq!(
+            payload,
+            r#"{"sequence":101,"timestamp":null,"tables":{},"truncated":true}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_xid_payload() -> MbLightResult<()> {
+        let mut tables = BTreeSet::from(["musicbrainz.artist".to_string()]);
+        let payload = xid_payload(ReplicationSequence(101), 7, &tables)?;
+        assert_eq!(
+            payload,
+            r#"{"sequence":101,"xid":7,"tables":["musicbrainz.artist"]}"#
+        );
+
+        for i in 0..400 {
+            tables.insert(format!("musicbrainz.table_{i}"));
+        }
+        let payload = xid_payload(ReplicationSequence(101), 7, &tables)?;
+        assert_eq!(
+            payload,
+            r#"{"sequence":101,"xid":7,"tables":[],"truncated":true}"#
+        );
+        Ok(())
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_notify`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_notify_applied_packet() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let mut listener = sqlx::postgres::PgListener::connect(&db_url).await?;
+        listener
+            .listen_all(["fixture_packets", "fixture_xids"])
+            .await?;
+
+        let mut settings = crate::settings::Settings::default();
+        settings.notify.channel = Some("fixture_packets".to_string());
+        settings.notify.xid_channel = Some("fixture_xids".to_string());
+        let mb_light = MbLight::try_new(settings, db_url).await?;
+        sqlx::raw_sql(
+            r#"DROP TABLE IF EXISTS musicbrainz.fixture_notify;
+               CREATE TABLE musicbrainz.fixture_notify (id INTEGER PRIMARY KEY, name VARCHAR NOT NULL);
+               TRUNCATE dbmirror2.pending_data, dbmirror2.pending_keys;
+               INSERT INTO dbmirror2.pending_keys VALUES ('musicbrainz.fixture_notify', '{id}');
+               INSERT INTO dbmirror2.pending_data (tablename, op, xid, olddata, newdata) VALUES
+                   ('musicbrainz.fixture_notify', 'i', 1, NULL, '{"id": 1, "name": "a"}'),
+                   ('musicbrainz.fixture_notify', 'i', 1, NULL, '{"id": 2, "name": "b"}'),
+                   ('musicbrainz.fixture_notify', 'd', 2, '{"id": 1, "name": "a"}', NULL);"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+
+        let sequence = ReplicationSequence(12);
+        let changes = mb_light.apply_pending_data(sequence).await?;
+        mb_light.notify_packet(sequence, None, &changes).await;
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let notification = listener.recv().await?;
+            received.push((
+                notification.channel().to_string(),
+                notification.payload().to_string(),
+            ));
+        }
+        assert_eq!(
+            received,
+            [
+                (
+                    "fixture_xids".to_string(),
+                    r#"{"sequence":12,"xid":1,"tables":["musicbrainz.fixture_notify"]}"#
+                        .to_string()
+                ),
+                (
+                    "fixture_xids".to_string(),
+                    r#"{"sequence":12,"xid":2,"tables":["musicbrainz.fixture_notify"]}"#
+                        .to_string()
+                ),
+                (
+                    "fixture_packets".to_string(),
+                    r#"{"sequence":12,"timestamp":null,"tables":{"musicbrainz.fixture_notify":{"inserts":2,"updates":0,"deletes":1}}}"#
+                        .to_string()
+                ),
+            ]
+        );
+
+        sqlx::raw_sql("DROP TABLE musicbrainz.fixture_notify")
+            .execute(&mb_light.db)
+            .await?;
+        Ok(())
+    }
+}
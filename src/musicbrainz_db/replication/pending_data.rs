@@ -1,8 +1,16 @@
-use serde_json::Value;
-use sqlx::{Postgres, Transaction, prelude::FromRow};
-use std::fmt;
-
-use crate::{MbLight, MbLightError, error::MbLightResult, settings::MbLightSettingsExt};
+use futures_util::stream::BoxStream;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{PgPool, Postgres, Transaction, prelude::FromRow};
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    MbLight, MbLightError,
+    error::MbLightResult,
+    musicbrainz_db::copy_text::{decode_field, split_row},
+    musicbrainz_db::replication::statement::{Statement, TableColumns, quote_ident},
+    settings::MbLightSettingsExt,
+};
 
 #[derive(FromRow, Debug)]
 pub struct PendingData {
@@ -14,7 +22,8 @@ pub struct PendingData {
     keys: Vec<String>,
 }
 
-#[derive(sqlx::Type, Debug)]
+#[derive(sqlx::Type, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 #[repr(i8)]
 pub enum Operation {
     Delete = b'd' as i8,
@@ -33,7 +42,68 @@ impl fmt::Display for Operation {
 }
 
 impl PendingData {
-    pub async fn all(db: &sqlx::PgPool) -> Result<Vec<Self>, sqlx::Error> {
+    #[cfg(test)]
+    pub fn new(
+        fulltable: &str,
+        op: Operation,
+        olddata: Option<Value>,
+        newdata: Option<Value>,
+    ) -> Self {
+        Self {
+            fulltable: fulltable.to_string(),
+            op,
+            xid: 1,
+            olddata,
+            newdata,
+            keys: vec!["id".to_string()],
+        }
+    }
+
+    /// Parses a `dbmirror2.pending_data` COPY text row, `keys` maps each replicated
+    /// table to its `dbmirror2.pending_keys` entry.
+    pub fn from_copy_row(row: &[u8], keys: &HashMap<String, Vec<String>>) -> MbLightResult<Self> {
+        // seqid, tablename, op, xid, olddata, newdata
+        let mut fields = split_row(row).skip(1).map(decode_field);
+        let mut next_field = |name| {
+            fields
+                .next()
+                .ok_or(MbLightError::MalformedPendingData(name))
+        };
+
+        let fulltable =
+            next_field("tablename")?.ok_or(MbLightError::MissingPendingData("tablename"))?;
+        let op = match next_field("op")?.as_deref() {
+            Some("i") => Operation::Insert,
+            Some("u") => Operation::Update,
+            Some("d") => Operation::Delete,
+            _ => return Err(MbLightError::MalformedPendingData("op")),
+        };
+        let xid = next_field("xid")?
+            .ok_or(MbLightError::MissingPendingData("xid"))?
+            .parse()?;
+        let olddata = next_field("olddata")?
+            .map(|data| serde_json::from_str(&data))
+            .transpose()?;
+        let newdata = next_field("newdata")?
+            .map(|data| serde_json::from_str(&data))
+            .transpose()?;
+        let keys = keys
+            .get(&fulltable)
+            .cloned()
+            .ok_or(MbLightError::MissingPendingData("keys"))?;
+
+        Ok(Self {
+            fulltable,
+            op,
+            xid,
+            olddata,
+            newdata,
+            keys,
+        })
+    }
+
+    /// Streams pending rows in xid and seqid order.
+    pub fn stream(db: &PgPool) -> BoxStream<'_, Result<Self, sqlx::Error>> {
         sqlx::query_as(
             r#"SELECT pd.xid,
                    pd.tablename as fulltable,
@@ -46,7 +116,17 @@ impl PendingData {
                 ON pk.tablename = pd.tablename
                  ORDER BY pd.xid, pd.seqid"#,
         )
-        .fetch_all(db)
+        .fetch(db)
+    }
+
+    pub async fn count(db: &PgPool) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"SELECT COUNT(*)
+              FROM dbmirror2.pending_data pd
+              JOIN dbmirror2.pending_keys pk
+                ON pk.tablename = pd.tablename"#,
+        )
+        .fetch_one(db)
         .await
     }
 
@@ -61,42 +141,79 @@ impl PendingData {
         Ok(tx)
     }
 
-    pub fn to_sql_inline(&self) -> MbLightResult<Option<String>> {
+    /// Renders this row as a standalone SQL statement with inlined literals.
+    pub fn to_sql_inline(&self, columns: &TableColumns) -> MbLightResult<Option<String>> {
+        Ok(self
+            .to_statement(columns)?
+            .map(|statement| format!("{};", statement.to_inline())))
+    }
+
+    /// Builds a parameterised statement for this row, casting values to the table column types.
+    pub fn to_statement(&self, columns: &TableColumns) -> MbLightResult<Option<Statement>> {
         let (schema, table) = self.split_table_schema();
+        let fulltable = format!("{}.{}", quote_ident(schema), quote_ident(table));
+        let mut statement = Statement::default();
+
         match self.op {
             Operation::Insert => {
-                let obj = self
-                    .newdata
-                    .as_ref()
-                    .ok_or_else(|| MbLightError::MissingPendingData("newdata"))?;
-                let obj = obj.as_object().unwrap();
-                let col_names: Vec<&str> = obj.keys().map(|k| k.as_str()).collect();
-                let col_values: Vec<String> = obj.values().map(sql_literal).collect();
-                Ok(Some(format!(
-                    r#"INSERT INTO "{schema}"."{table}" ({}) VALUES ({});"#,
+                let new_obj = self.new_obj()?;
+                let mut col_names = Vec::with_capacity(new_obj.len());
+                let mut placeholders = Vec::with_capacity(new_obj.len());
+                for (k, v) in new_obj {
+                    col_names.push(quote_ident(k));
+                    placeholders.push(statement.push_param(columns, k, v)?);
+                }
+
+                statement.sql = format!(
+                    "INSERT INTO {fulltable} ({}) VALUES ({})",
                     col_names.join(", "),
-                    col_values.join(", ")
-                )))
+                    placeholders.join(", ")
+                );
             }
             Operation::Update => {
-                let Some(set_clause) = self.get_set_clause()? else {
-                    return Ok(None);
-                };
+                let new_obj = self.new_obj()?;
+                let mut changes = Vec::new();
+                for k in self.changed_columns()? {
+                    let placeholder = statement.push_param(columns, k, &new_obj[k])?;
+                    changes.push(format!("{} = {placeholder}", quote_ident(k)));
+                }
 
-                let where_clause = self.get_where_clause()?;
+                if changes.is_empty() {
+                    return Ok(None);
+                }
 
-                Ok(Some(format!(
-                    r#"UPDATE "{schema}"."{table}" SET {set_clause} WHERE {where_clause};"#,
-                )))
+                let where_clause = self.push_where_clause(&mut statement, columns)?;
+                statement.sql = format!(
+                    "UPDATE {fulltable} SET {} WHERE {where_clause}",
+                    changes.join(", ")
+                );
             }
             Operation::Delete => {
-                let where_clause = self.get_where_clause()?;
-
-                Ok(Some(format!(
-                    r#"DELETE FROM "{schema}"."{table}" WHERE {where_clause};"#,
-                )))
+                let where_clause = self.push_where_clause(&mut statement, columns)?;
+                statement.sql = format!("DELETE FROM {fulltable} WHERE {where_clause}");
             }
         }
+
+        Ok(Some(statement))
+    }
+
+    pub fn fulltable(&self) -> &str {
+        &self.fulltable
+    }
+
+    pub fn op(&self) -> Operation {
+        self.op
+    }
+
+    /// Columns whose value differs between `olddata` and `newdata`.
+    pub fn changed_columns(&self) -> MbLightResult<Vec<&str>> {
+        let new_obj = self.new_obj()?;
+        let old_obj = self.old_obj()?;
+        Ok(new_obj
+            .iter()
+            .filter(|(k, new_val)| old_obj.get(*k) != Some(new_val))
+            .map(|(k, _)| k.as_str())
+            .collect())
     }
 
     pub fn split_table_schema(&self) -> (&str, &str) {
@@ -104,58 +221,81 @@ impl PendingData {
         (parts[0], parts[1])
     }
 
-    fn get_where_clause(&self) -> MbLightResult<String> {
-        let old_obj = self
-            .olddata
-            .as_ref()
-            .ok_or_else(|| MbLightError::MissingPendingData("olddata"))?
-            .as_object()
-            .ok_or_else(|| MbLightError::MalformedPendingData("olddata"))?;
+    fn push_where_clause(
+        &self,
+        statement: &mut Statement,
+        columns: &TableColumns,
+    ) -> MbLightResult<String> {
+        let old_obj = self.old_obj()?;
+        let mut conditions = Vec::new();
+        for key in self.sanitized_keys() {
+            let value = old_obj
+                .get(key)
+                .ok_or(MbLightError::MalformedPendingData("keys"))?;
+            let placeholder = statement.push_param(columns, key, value)?;
+            conditions.push(format!("{} = {placeholder}", quote_ident(key)));
+        }
 
-        let where_clause: Vec<String> = old_obj
-            .iter()
-            .filter(|(k, _)| self.sanitized_keys().contains(&k.as_str()))
-            .map(|(k, v)| format!("{k} = {}", sql_literal(v)))
-            .collect();
+        if conditions.is_empty() {
+            return Err(MbLightError::MissingPendingData("keys"));
+        }
 
-        Ok(where_clause.join(" AND "))
+        Ok(conditions.join(" AND "))
     }
 
-    fn get_set_clause(&self) -> MbLightResult<Option<String>> {
-        let new_obj = self
-            .newdata
-            .as_ref()
-            .ok_or_else(|| MbLightError::MissingPendingData("newdata"))?;
+    pub fn olddata(&self) -> Option<&Value> {
+        self.olddata.as_ref()
+    }
 
-        let new_obj = new_obj.as_object().unwrap();
+    pub fn newdata(&self) -> Option<&Value> {
+        self.newdata.as_ref()
+    }
 
-        let old_obj = self
-            .olddata
+    pub fn new_obj(&self) -> MbLightResult<&Map<String, Value>> {
+        self.newdata
             .as_ref()
-            .ok_or_else(|| MbLightError::MissingPendingData("olddata"))?
+            .ok_or(MbLightError::MissingPendingData("newdata"))?
             .as_object()
-            .ok_or_else(|| MbLightError::MalformedPendingData("olddata"))?;
+            .ok_or(MbLightError::MalformedPendingData("newdata"))
+    }
 
-        let mut changes = Vec::new();
+    pub fn old_obj(&self) -> MbLightResult<&Map<String, Value>> {
+        self.olddata
+            .as_ref()
+            .ok_or(MbLightError::MissingPendingData("olddata"))?
+            .as_object()
+            .ok_or(MbLightError::MalformedPendingData("olddata"))
+    }
 
-        for (k, new_val) in new_obj {
-            if let Some(old_val) = old_obj.get(k) {
-                if old_val != new_val {
-                    changes.push(format!(r#"{k} = {}"#, sql_literal(new_val)));
-                }
-            } else {
-                changes.push(format!(r#"{k} = {}"#, sql_literal(new_val)));
-            }
+    /// Key columns of the row and their value, taken from `newdata` for an insert.
+    pub fn primary_key(&self) -> MbLightResult<Map<String, Value>> {
+        match self.op {
+            Operation::Insert => self.key_of(self.new_obj()?),
+            Operation::Update | Operation::Delete => self.key_of(self.old_obj()?),
         }
+    }
 
-        if changes.is_empty() {
+    /// The new key of an update that changes the primary key of its row.
+    pub fn changed_primary_key(&self) -> MbLightResult<Option<Map<String, Value>>> {
+        if self.op != Operation::Update {
             return Ok(None);
         }
+        let new_key = self.key_of(self.new_obj()?)?;
+        Ok((new_key != self.key_of(self.old_obj()?)?).then_some(new_key))
+    }
 
-        Ok(Some(changes.join(", ")))
+    fn key_of(&self, obj: &Map<String, Value>) -> MbLightResult<Map<String, Value>> {
+        self.sanitized_keys()
+            .into_iter()
+            .map(|key| {
+                obj.get(key)
+                    .map(|value| (key.to_string(), value.clone()))
+                    .ok_or(MbLightError::MalformedPendingData("keys"))
+            })
+            .collect()
     }
 
-    fn sanitized_keys(&self) -> Vec<&str> {
+    pub fn sanitized_keys(&self) -> Vec<&str> {
         self.keys
             .iter()
             .map(|key| key.trim_matches(['{', '}']))
@@ -163,29 +303,6 @@ impl PendingData {
     }
 }
 
-fn sql_literal(val: &serde_json::Value) -> String {
-    match val {
-        serde_json::Value::String(s) => format!("'{}'", s.replace('\'', "''")),
-        serde_json::Value::Number(n) => n.to_string(),
-        serde_json::Value::Bool(b) => b.to_string(),
-        serde_json::Value::Null => "NULL".to_string(),
-        serde_json::Value::Array(arr) => {
-            let elems: Vec<String> = arr
-                .iter()
-                .map(|v| match v {
-                    serde_json::Value::Number(n) => n.to_string(),
-                    serde_json::Value::String(s) => s.replace('\'', "''"),
-                    _ => panic!("unsupported array element: {:?}", v),
-                })
-                .collect();
-            format!("'{{{}}}'::integer[]", elems.join(","))
-        }
-        serde_json::Value::Object(_) => {
-            format!("'{}'", val.to_string().replace('\'', "''"))
-        }
-    }
-}
-
 impl<S: MbLightSettingsExt> MbLight<S> {
     pub async fn truncate_pending_data(&self) -> Result<(), sqlx::Error> {
         sqlx::query(r#"TRUNCATE TABLE dbmirror2.pending_data"#)
@@ -237,9 +354,130 @@ mod tests {
             keys: vec!["id".to_string()],
         };
 
-        let query = pd.to_sql_inline()?;
+        let query = pd.to_sql_inline(&TableColumns::default())?;
 
         assert!(query.is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_insert_statement_casts_columns() -> MbLightResult<()> {
+        let columns = TableColumns::from([
+            ("id", "int4"),
+            ("gid", "uuid"),
+            ("name", "varchar"),
+            ("aliases", "text[]"),
+            ("extra", "jsonb"),
+        ]);
+
+        let pd = PendingData {
+            fulltable: "musicbrainz.artist".to_string(),
+            op: Operation::Insert,
+            xid: 1,
+            olddata: None,
+            newdata: Some(serde_json::json!({
+                "id": 1,
+                "gid": "89ad4ac3-39f7-470e-963a-56509c546377",
+                "name": "Guns N' Roses",
+                "aliases": ["GNR", "G\"N\"R"],
+                "extra": {"a": [1, 2]}
+            })),
+            keys: vec!["id".to_string()],
+        };
+
+        let statement = pd.to_statement(&columns)?.unwrap();
+
+        assert_eq!(
+            statement.sql,
+            r#"INSERT INTO "musicbrainz"."artist" ("aliases", "extra", "gid", "id", "name") VALUES ($1::text[], $2::jsonb, $3::uuid, $4::int4, $5::varchar)"#
+        );
+        assert_eq!(
+            statement.params,
+            vec![
+                Some(r#"{"GNR","G\"N\"R"}"#.to_string()),
+                Some(r#"{"a":[1,2]}"#.to_string()),
+                Some("89ad4ac3-39f7-470e-963a-56509c546377".to_string()),
+                Some("1".to_string()),
+                Some("Guns N' Roses".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_statement_binds_keys_last() -> MbLightResult<()> {
+        let columns = TableColumns::from([("id", "int4"), ("name", "varchar")]);
+
+        let pd = PendingData {
+            fulltable: "musicbrainz.artist".to_string(),
+            op: Operation::Update,
+            xid: 1,
+            olddata: Some(serde_json::json!({"id": 1, "name": "old"})),
+            newdata: Some(serde_json::json!({"id": 1, "name": "new"})),
+            keys: vec!["{id}".to_string()],
+        };
+
+        let statement = pd.to_statement(&columns)?.unwrap();
+
+        assert_eq!(
+            statement.sql,
+            r#"UPDATE "musicbrainz"."artist" SET "name" = $1::varchar WHERE "id" = $2::int4"#
+        );
+        assert_eq!(
+            statement.params,
+            vec![Some("new".to_string()), Some("1".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_primary_key_of_applied_rows() -> MbLightResult<()> {
+        let mut rows = crate::TableRows::default();
+        for (op, olddata, newdata) in [
+            (
+                Operation::Insert,
+                None,
+                Some(serde_json::json!({"id": 1, "name": "a"})),
+            ),
+            (
+                Operation::Update,
+                Some(serde_json::json!({"id": 2, "name": "b"})),
+                Some(serde_json::json!({"id": 3, "name": "c"})),
+            ),
+            (Operation::Delete, Some(serde_json::json!({"id": 4})), None),
+        ] {
+            let data = PendingData::new("musicbrainz.artist", op, olddata, newdata);
+            rows.record(&data, true)?;
+        }
+
+        assert_eq!(rows.changes.total(), 3);
+        assert_eq!(rows.changes.updates, 1);
+        // Both keys of the update moving row 2 to 3
+        let ids: Vec<&Value> = rows.keys.iter().map(|key| &key["id"]).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+
+        let mut counted = crate::TableRows::default();
+        let data = PendingData::new(
+            "musicbrainz.artist",
+            Operation::Update,
+            Some(serde_json::json!({"id": 2, "name": "b"})),
+            Some(serde_json::json!({"id": 2, "name": "c"})),
+        );
+        assert_eq!(data.changed_primary_key()?, None);
+        counted.record(&data, false)?;
+        assert_eq!(counted.changes.updates, 1);
+        assert!(counted.keys.is_empty());
+
+        let data = PendingData::new(
+            "musicbrainz.artist",
+            Operation::Delete,
+            Some(serde_json::json!({"name": "d"})),
+            None,
+        );
+        assert!(matches!(
+            data.primary_key(),
+            Err(MbLightError::MalformedPendingData("keys"))
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+//! Refresh of the derived tables and materialized views built on top of the mirror.
+
+use std::{fmt, fs, path::Path, time::Instant};
+
+use tracing::{error, info};
+
+use crate::{
+    MbLight, MbLightEvent,
+    error::MbLightResult,
+    musicbrainz_db::replication::statement::quote_ident,
+    settings::{MbLightSettingsExt, PostSyncSettings},
+};
+
+enum PostSyncStep<'a> {
+    SqlFile(&'a Path),
+    Refresh { view: &'a str, concurrently: bool },
+}
+
+impl fmt::Display for PostSyncStep<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostSyncStep::SqlFile(path) => write!(f, "SQL file {}", path.display()),
+            PostSyncStep::Refresh {
+                view,
+                concurrently: true,
+            } => write!(f, "concurrent refresh of {view}"),
+            PostSyncStep::Refresh { view, .. } => write!(f, "refresh of {view}"),
+        }
+    }
+}
+
+impl<'a> PostSyncStep<'a> {
+    fn all(settings: &'a PostSyncSettings) -> impl Iterator<Item = Self> {
+        let sql_files = settings.sql_files.iter().map(|path| Self::SqlFile(path));
+        let refresh = settings.refresh.iter().map(|view| Self::Refresh {
+            view,
+            concurrently: false,
+        });
+        let refresh_concurrently = settings
+            .refresh_concurrently
+            .iter()
+            .map(|view| Self::Refresh {
+                view,
+                concurrently: true,
+            });
+        sql_files.chain(refresh).chain(refresh_concurrently)
+    }
+}
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Runs the [`MbLightSettingsExt::post_sync`] steps. A failing step is rolled back, logged
+    /// and reported as [`MbLightEvent::PostSyncFailed`] without stopping the next ones, so a
+    /// broken view does not hold replication back. Returns the number of failed steps.
+    pub async fn post_sync(&self) -> usize {
+        let Some(settings) = self.config.post_sync() else {
+            return 0;
+        };
+
+        let mut failed = 0;
+        for step in PostSyncStep::all(settings) {
+            let started = Instant::now();
+            match self.run_post_sync_step(&step).await {
+                Ok(()) => info!("Post-sync {} done in {:?}", step, started.elapsed()),
+                Err(err) => {
+                    error!("Post-sync {} failed: {}", step, err);
+                    self.emit(MbLightEvent::PostSyncFailed {
+                        step: step.to_string(),
+                        error: err.to_string(),
+                    });
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    async fn run_post_sync_step(&self, step: &PostSyncStep<'_>) -> MbLightResult<()> {
+        let sql = match step {
+            PostSyncStep::SqlFile(path) => fs::read_to_string(path)?
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('\\'))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            PostSyncStep::Refresh { view, concurrently } => format!(
+                "REFRESH MATERIALIZED VIEW {}{}",
+                if *concurrently { "CONCURRENTLY " } else { "" },
+                quote_view(view)
+            ),
+        };
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("SET LOCAL search_path TO musicbrainz, public")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::raw_sql(&sql).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Quotes each part of a possibly schema qualified view name.
+fn quote_view(view: &str) -> String {
+    view.split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps_order() {
+        let settings = PostSyncSettings {
+            sql_files: vec!["derived.sql".into()],
+            refresh: vec!["musicbrainz.artist_stats".to_string()],
+            refresh_concurrently: vec!["release_stats".to_string()],
+            ..Default::default()
+        };
+
+        let steps: Vec<String> = PostSyncStep::all(&settings)
+            .map(|step| step.to_string())
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "SQL file derived.sql",
+                "refresh of musicbrainz.artist_stats",
+                "concurrent refresh of release_stats",
+            ]
+        );
+        assert_eq!(
+            quote_view("musicbrainz.artist_stats"),
+            r#""musicbrainz"."artist_stats""#
+        );
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_post_sync`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_post_sync_isolates_failures() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let dir = tempfile::tempdir()?;
+        let script = dir.path().join("derived.sql");
+        fs::write(
+            &script,
+            "\\set ON_ERROR_STOP 1\nINSERT INTO fixture_post_sync VALUES (2);\n",
+        )?;
+
+        let mut settings = crate::settings::Settings::default();
+        settings.post_sync.sql_files = vec![dir.path().join("missing.sql"), script];
+        settings.post_sync.refresh = vec![
+            "musicbrainz.fixture_post_sync_broken".to_string(),
+            "musicbrainz.fixture_post_sync_count".to_string(),
+        ];
+        settings.post_sync.refresh_concurrently = vec!["fixture_post_sync_count".to_string()];
+        let mb_light = MbLight::try_new(settings, db_url).await?;
+        let mut events = mb_light.subscribe();
+        sqlx::raw_sql(
+            r#"DROP MATERIALIZED VIEW IF EXISTS musicbrainz.fixture_post_sync_count;
+               DROP TABLE IF EXISTS musicbrainz.fixture_post_sync;
+               CREATE TABLE musicbrainz.fixture_post_sync (id INTEGER PRIMARY KEY);
+               INSERT INTO musicbrainz.fixture_post_sync VALUES (1);
+               CREATE MATERIALIZED VIEW musicbrainz.fixture_post_sync_count AS
+                   SELECT 1 AS id, count(*) AS total FROM musicbrainz.fixture_post_sync;
+               CREATE UNIQUE INDEX ON musicbrainz.fixture_post_sync_count (id);"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+
+        assert_eq!(mb_light.post_sync().await, 2);
+        let total: i64 =
+            sqlx::query_scalar("SELECT total FROM musicbrainz.fixture_post_sync_count")
+                .fetch_one(&mb_light.db)
+                .await?;
+        assert_eq!(total, 2);
+        for step in [
+            format!("SQL file {}", dir.path().join("missing.sql").display()),
+            "refresh of musicbrainz.fixture_post_sync_broken".to_string(),
+        ] {
+            assert!(matches!(
+                events.try_recv(),
+                Ok(MbLightEvent::PostSyncFailed { step: failed, .. }) if failed == step
+            ));
+        }
+
+        sqlx::raw_sql(
+            r#"DROP MATERIALIZED VIEW musicbrainz.fixture_post_sync_count;
+               DROP TABLE musicbrainz.fixture_post_sync;"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+        Ok(())
+    }
+}
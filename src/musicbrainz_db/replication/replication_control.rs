@@ -1,53 +1,306 @@
+use std::fmt;
+
+use serde::Serialize;
 use sqlx::{
-    PgPool,
+    PgExecutor, PgPool,
     prelude::FromRow,
     types::chrono::{DateTime, Utc},
 };
 
-use crate::{MbLightError, error::MbLightResult};
+use crate::{MbLightError, error::MbLightResult, musicbrainz_db::dump_metadata::DumpMetadata};
+
+/// Version of the database schema, bumped by each MusicBrainz schema change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::Type, Serialize)]
+#[sqlx(transparent)]
+pub struct SchemaSequence(pub i32);
+
+/// Number of a replication packet, each packet follows the previous one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::Type, Serialize)]
+#[sqlx(transparent)]
+pub struct ReplicationSequence(pub i32);
+
+impl SchemaSequence {
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl ReplicationSequence {
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for SchemaSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for ReplicationSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// What applying a packet takes, on top of loading its changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaTransition {
+    /// The packet was exported with the current schema.
+    Unchanged,
+    /// The packet was exported after a schema change, the mirror is upgraded first.
+    Upgrade(SchemaSequence),
+}
 
-#[derive(Debug, FromRow)]
+/// The sequences of the last applied packet, stored in the single row of `replication_control`
+/// along with the time it was exported.
+///
+/// Packets are applied one after the other: a packet is accepted by
+/// [`ReplicationControl::advance`] when its replication sequence follows the current one, and
+/// its schema sequence is the current one or the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRow)]
 pub struct ReplicationControl {
-    pub current_schema_sequence: Option<i32>,
-    pub current_replication_sequence: Option<i32>,
+    pub current_schema_sequence: Option<SchemaSequence>,
+    pub current_replication_sequence: Option<ReplicationSequence>,
     pub last_replication_date: Option<DateTime<Utc>>,
 }
 
 impl ReplicationControl {
     pub async fn get(db: &PgPool) -> Result<Self, sqlx::Error> {
         sqlx::query_as(
-            "SELECT current_schema_sequence, current_replication_sequence, last_replication_date FROM replication_control"
+            "SELECT current_schema_sequence, current_replication_sequence, last_replication_date FROM musicbrainz.replication_control"
         )
         .fetch_one(db)
         .await
     }
 
-    pub async fn update(self, db: &PgPool) -> MbLightResult<()> {
+    /// Records the packet reached by [`ReplicationControl::advance`] as applied.
+    pub async fn update(&self, db: &PgPool) -> MbLightResult<()> {
         sqlx::query(
-            "UPDATE replication_control SET current_replication_sequence = $1, last_replication_date = NOW()",
+            r#"UPDATE musicbrainz.replication_control
+               SET current_schema_sequence = $1, current_replication_sequence = $2, last_replication_date = $3"#,
+        )
+        .bind(self.current_schema_sequence)
+        .bind(self.current_replication_sequence)
+        .bind(self.last_replication_date)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Points replication at the packet following the dump described by `metadata`.
+    pub async fn set_from_dump(db: &PgPool, metadata: &DumpMetadata) -> MbLightResult<()> {
+        let updated = sqlx::query(
+            r#"UPDATE musicbrainz.replication_control
+               SET current_schema_sequence = $1, current_replication_sequence = $2, last_replication_date = $3"#,
         )
-        .bind(self.next_replication_sequence()?)
+        .bind(metadata.schema_sequence)
+        .bind(metadata.replication_sequence)
+        .bind(metadata.timestamp)
         .execute(db)
         .await?;
+
+        // The table is empty when it was skipped by the import
+        if updated.rows_affected() == 0 {
+            sqlx::query(
+                r#"INSERT INTO musicbrainz.replication_control (current_schema_sequence, current_replication_sequence, last_replication_date)
+                   VALUES ($1, $2, $3)"#,
+            )
+            .bind(metadata.schema_sequence)
+            .bind(metadata.replication_sequence)
+            .bind(metadata.timestamp)
+            .execute(db)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a schema change, from within its transaction.
+    pub async fn set_schema_sequence(
+        executor: impl PgExecutor<'_>,
+        schema_sequence: SchemaSequence,
+    ) -> MbLightResult<()> {
+        sqlx::query("UPDATE musicbrainz.replication_control SET current_schema_sequence = $1")
+            .bind(schema_sequence)
+            .execute(executor)
+            .await?;
         Ok(())
     }
 
-    pub fn next_replication_sequence(&self) -> MbLightResult<i32> {
+    pub fn next_replication_sequence(&self) -> MbLightResult<ReplicationSequence> {
         self.current_replication_sequence
-            .map(|seq| seq + 1)
+            .map(ReplicationSequence::next)
             .ok_or(MbLightError::MissingRepplicationSequence)
     }
 
-    pub fn is_next(&self, expected: i32) -> MbLightResult<bool> {
-        Ok(self.next_replication_sequence()? + 1 == expected)
+    /// Checks a packet exported with `schema_sequence` can be applied to the current schema.
+    pub fn schema_transition(
+        &self,
+        schema_sequence: SchemaSequence,
+    ) -> MbLightResult<SchemaTransition> {
+        let current = self
+            .current_schema_sequence
+            .ok_or(MbLightError::MissingSchemaSequence)?;
+        if schema_sequence == current {
+            Ok(SchemaTransition::Unchanged)
+        } else if schema_sequence == current.next() {
+            Ok(SchemaTransition::Upgrade(schema_sequence))
+        } else {
+            Err(MbLightError::SchemaMissmatch {
+                expected: current.0,
+                got: schema_sequence.0,
+            })
+        }
     }
 
-    pub fn schema_sequence_match(&self, actual: i32) -> bool {
-        self.current_schema_sequence == Some(actual)
+    /// Checks `replication_sequence` is the packet following the last applied one.
+    pub fn check_replication_sequence(
+        &self,
+        replication_sequence: ReplicationSequence,
+    ) -> MbLightResult<()> {
+        let expected = self.next_replication_sequence()?;
+        if replication_sequence != expected {
+            return Err(MbLightError::SequenceMissmatch {
+                expected: expected.0,
+                got: replication_sequence.0,
+            });
+        }
+        Ok(())
+    }
+
+    /// Moves to the packet described by `packet` if it is the next one to apply. The new state
+    /// is only persisted by [`ReplicationControl::update`], once the packet is applied.
+    pub fn advance(&mut self, packet: &DumpMetadata) -> MbLightResult<SchemaTransition> {
+        self.check_replication_sequence(packet.replication_sequence)?;
+        let transition = self.schema_transition(packet.schema_sequence)?;
+        self.current_schema_sequence = Some(packet.schema_sequence);
+        self.current_replication_sequence = Some(packet.replication_sequence);
+        self.last_replication_date = Some(packet.timestamp);
+        Ok(transition)
+    }
+
+    /// Moves past the packet left in `dbmirror2.pending_data` by an interrupted run, which was
+    /// checked and had its schema change applied before being loaded. Returns its sequence.
+    ///
+    /// Its export time is unknown, the date of the previous packet is kept.
+    pub fn advance_pending(&mut self) -> MbLightResult<ReplicationSequence> {
+        let sequence = self.next_replication_sequence()?;
+        self.current_replication_sequence = Some(sequence);
+        Ok(sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(schema: i32, replication: i32) -> ReplicationControl {
+        ReplicationControl {
+            current_schema_sequence: Some(SchemaSequence(schema)),
+            current_replication_sequence: Some(ReplicationSequence(replication)),
+            last_replication_date: None,
+        }
+    }
+
+    fn applied(schema: i32, replication: i32) -> ReplicationControl {
+        ReplicationControl {
+            last_replication_date: Some(DateTime::UNIX_EPOCH),
+            ..control(schema, replication)
+        }
     }
 
-    pub fn next_replication_packet_url(&self, base: &str, token: &str) -> MbLightResult<String> {
-        let seq = self.next_replication_sequence()?;
-        Ok(format!("{base}/replication-{seq}-v2.tar.bz2?token={token}"))
+    fn packet(schema: i32, replication: i32) -> DumpMetadata {
+        DumpMetadata {
+            timestamp: DateTime::UNIX_EPOCH,
+            schema_sequence: SchemaSequence(schema),
+            replication_sequence: ReplicationSequence(replication),
+        }
+    }
+
+    #[test]
+    fn test_advance_normal_packets() -> MbLightResult<()> {
+        let mut state = control(30, 100);
+        for replication in 101..=103 {
+            assert_eq!(
+                state.advance(&packet(30, replication))?,
+                SchemaTransition::Unchanged
+            );
+        }
+        assert_eq!(state, applied(30, 103));
+        assert_eq!(state.next_replication_sequence()?, ReplicationSequence(104));
+        Ok(())
+    }
+
+    #[test]
+    fn test_advance_schema_bump() -> MbLightResult<()> {
+        let mut state = control(29, 100);
+        assert_eq!(
+            state.advance(&packet(30, 101))?,
+            SchemaTransition::Upgrade(SchemaSequence(30))
+        );
+        assert_eq!(
+            state.advance(&packet(30, 102))?,
+            SchemaTransition::Unchanged
+        );
+        assert_eq!(state, applied(30, 102));
+        Ok(())
+    }
+
+    #[test]
+    fn test_advance_skipped_or_replayed_packet() {
+        for replication in [99, 100, 102, 150] {
+            let mut state = control(30, 100);
+            assert!(matches!(
+                state.advance(&packet(30, replication)),
+                Err(MbLightError::SequenceMissmatch { expected: 101, got }) if got == replication
+            ));
+            assert_eq!(state, control(30, 100));
+        }
+    }
+
+    #[test]
+    fn test_advance_schema_missmatch() {
+        for schema in [28, 29, 32] {
+            let mut state = control(30, 100);
+            assert!(matches!(
+                state.advance(&packet(schema, 101)),
+                Err(MbLightError::SchemaMissmatch { expected: 30, got }) if got == schema
+            ));
+            assert_eq!(state, control(30, 100));
+        }
+    }
+
+    #[test]
+    fn test_advance_uninitialised() {
+        let mut state = control(30, 100);
+        state.current_replication_sequence = None;
+        assert!(matches!(
+            state.advance(&packet(30, 101)),
+            Err(MbLightError::MissingRepplicationSequence)
+        ));
+
+        let mut state = control(30, 100);
+        state.current_schema_sequence = None;
+        assert!(matches!(
+            state.advance(&packet(30, 101)),
+            Err(MbLightError::MissingSchemaSequence)
+        ));
+        assert_eq!(
+            state.current_replication_sequence,
+            Some(ReplicationSequence(100))
+        );
+    }
+
+    #[test]
+    fn test_advance_pending() -> MbLightResult<()> {
+        let mut state = control(30, 100);
+        assert_eq!(state.advance_pending()?, ReplicationSequence(101));
+        assert_eq!(state, control(30, 101));
+        assert_eq!(
+            state.advance(&packet(31, 102))?,
+            SchemaTransition::Upgrade(SchemaSequence(31))
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,413 @@
+//! Schema changes announced by a replication packet, applied the way `admin/upgrade.sh`
+//! upgrades a mirror.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, info};
+
+use crate::{
+    MbLight, MbLightError,
+    download::github::SqlPurpose,
+    error::MbLightResult,
+    musicbrainz_db::{
+        init::SCHEMAS,
+        replication::replication_control::{ReplicationControl, SchemaSequence},
+    },
+    settings::MbLightSettingsExt,
+};
+
+/// Location of the schema change scripts in `admin/sql`.
+pub(crate) const SCHEMA_CHANGE_DIR: &str = "updates/schema-change";
+
+/// Scripts run on a mirror, in order: `<N>.all.sql` then `<N>.mirror.sql`, which was named
+/// `<N>.slave.sql` by older schema changes.
+pub(crate) const MIRROR_SCRIPTS: &[&[&str]] = &[&["all"], &["mirror", "slave"]];
+
+impl<S: MbLightSettingsExt> MbLight<S> {
+    /// Upgrades the mirror to `schema_sequence`. Its scripts and the `replication_control`
+    /// update run in a single transaction, so a failed upgrade leaves the schema untouched.
+    pub async fn migrate_schema(&self, schema_sequence: SchemaSequence) -> MbLightResult<()> {
+        let statements = self.schema_change_statements(schema_sequence).await?;
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("SET LOCAL search_path TO musicbrainz, public")
+            .execute(&mut *tx)
+            .await?;
+        for statement in &statements {
+            debug!("{statement}");
+            sqlx::raw_sql(statement).execute(&mut *tx).await?;
+        }
+        ReplicationControl::set_schema_sequence(&mut *tx, schema_sequence).await?;
+        tx.commit().await?;
+
+        info!("Schema updated to version {schema_sequence}");
+        Ok(())
+    }
+
+    /// Statements of the mirror scripts of `schema_sequence` that touch the kept schemas.
+    pub(crate) async fn schema_change_statements(
+        &self,
+        schema_sequence: SchemaSequence,
+    ) -> MbLightResult<Vec<String>> {
+        let admin_sql = self
+            .admin_sql(schema_sequence, None, SqlPurpose::SchemaChange)
+            .await?;
+        let mut statements = Vec::new();
+        for script in mirror_scripts(&admin_sql.join(SCHEMA_CHANGE_DIR), schema_sequence)? {
+            info!("Reading schema change {}", script.display());
+            let sql = fs::read_to_string(&script)?;
+            statements.extend(kept_statements(&sql, |schema| {
+                self.config.should_skip_schema(schema)
+            }));
+        }
+
+        Ok(statements)
+    }
+}
+
+/// The scripts of `dir` a mirror runs to reach `schema_sequence`, in order.
+fn mirror_scripts(dir: &Path, schema_sequence: SchemaSequence) -> MbLightResult<Vec<PathBuf>> {
+    let scripts: Vec<PathBuf> = MIRROR_SCRIPTS
+        .iter()
+        .filter_map(|names| {
+            names
+                .iter()
+                .map(|name| dir.join(format!("{schema_sequence}.{name}.sql")))
+                .find(|path| path.is_file())
+        })
+        .collect();
+
+    if scripts.is_empty() {
+        return Err(MbLightError::MissingSqlScripts(
+            dir.join(format!("{schema_sequence}.all.sql"))
+                .display()
+                .to_string(),
+        ));
+    }
+
+    Ok(scripts)
+}
+
+/// Statements of `sql` to run on the mirror: transaction control is left to the caller, and
+/// statements on a skipped schema are dropped.
+///
+/// A statement belongs to the schemas it qualifies names with, or else to the first schema
+/// of the current `search_path`. `SET search_path` is made `LOCAL`, so it ends with the
+/// transaction instead of sticking to the pooled connection.
+fn kept_statements(sql: &str, should_skip_schema: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut current_schema = "musicbrainz".to_string();
+    split_statements(sql)
+        .into_iter()
+        .filter_map(|statement| {
+            if is_transaction_control(&statement) {
+                return None;
+            }
+            if let Some(schema) = search_path_schema(&statement) {
+                current_schema = schema;
+                return Some(set_local(&statement));
+            }
+
+            let mut schemas = SCHEMAS
+                .iter()
+                .filter(|schema| qualifies_with(&statement, schema))
+                .peekable();
+            let kept = if schemas.peek().is_none() {
+                !should_skip_schema(&current_schema)
+            } else {
+                !schemas.any(|schema| should_skip_schema(schema))
+            };
+            kept.then_some(statement)
+        })
+        .collect()
+}
+
+/// Splits a script on the `;` ending its statements, outside of quotes, dollar quoted bodies
+/// and comments. Comments and psql meta-commands are dropped.
+fn split_statements(sql: &str) -> Vec<String> {
+    let sql = sql
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('\\'))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+    let mut rest = sql.as_str();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '\'' | '"' => rest[1..].find(c).map_or(rest.len(), |end| end + 2),
+            '$' => match dollar_tag(rest) {
+                Some(tag) => rest[tag.len()..]
+                    .find(tag)
+                    .map_or(rest.len(), |end| end + 2 * tag.len()),
+                None => 1,
+            },
+            '-' if rest.starts_with("--") => {
+                rest = rest.find('\n').map_or("", |end| &rest[end..]);
+                continue;
+            }
+            '/' if rest.starts_with("/*") => {
+                rest = rest.find("*/").map_or("", |end| &rest[end + 2..]);
+                statement.push(' ');
+                continue;
+            }
+            ';' => {
+                statements.push(statement.trim().to_string());
+                statement.clear();
+                rest = &rest[1..];
+                continue;
+            }
+            c => c.len_utf8(),
+        };
+        statement.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    statements.push(statement.trim().to_string());
+
+    statements.retain(|statement| !statement.is_empty());
+    statements
+}
+
+/// The `$tag$` opening a dollar quoted string at the start of `sql`.
+fn dollar_tag(sql: &str) -> Option<&str> {
+    let end = sql[1..].find('$')? + 2;
+    let tag = &sql[..end];
+    let name = &tag[1..end - 1];
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    valid.then_some(tag)
+}
+
+fn is_transaction_control(statement: &str) -> bool {
+    let statement = statement.to_ascii_lowercase();
+    matches!(
+        statement.split_whitespace().collect::<Vec<_>>().as_slice(),
+        ["begin"]
+            | ["begin", "transaction" | "work"]
+            | ["start", "transaction"]
+            | ["commit"]
+            | ["commit", "transaction" | "work"]
+            | ["end"]
+    )
+}
+
+/// First schema of a `SET search_path` statement.
+fn search_path_schema(statement: &str) -> Option<String> {
+    let statement = statement.to_ascii_lowercase();
+    let rest = statement.strip_prefix("set ")?.trim_start();
+    let rest = rest.strip_prefix("local ").unwrap_or(rest).trim_start();
+    let rest = rest.strip_prefix("search_path")?.trim_start();
+    let rest = rest
+        .strip_prefix('=')
+        .or_else(|| rest.strip_prefix("to "))?;
+    let schema = rest.split(',').next()?.trim().trim_matches(['\'', '"']);
+    Some(schema.to_string())
+}
+
+/// `SET LOCAL` form of a `SET search_path` statement.
+fn set_local(statement: &str) -> String {
+    let rest = statement["set".len()..].trim_start();
+    if rest.to_ascii_lowercase().starts_with("local ") {
+        statement.to_string()
+    } else {
+        format!("SET LOCAL {rest}")
+    }
+}
+
+/// Whether `statement` names an object of `schema` as `schema.name`.
+fn qualifies_with(statement: &str, schema: &str) -> bool {
+    statement.match_indices(schema).any(|(start, _)| {
+        let before = statement[..start].chars().next_back();
+        let after = statement[start + schema.len()..].chars().next();
+        after == Some('.') && !before.is_some_and(|c| c == '_' || c.is_alphanumeric())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    const SCHEMA_CHANGE: &str = r#"\set ON_ERROR_STOP 1
+BEGIN;
+SET search_path = musicbrainz, public;
+
+-- Adds a column; on the release table
+ALTER TABLE release ADD COLUMN note TEXT DEFAULT 'it''s; fine';
+
+CREATE OR REPLACE FUNCTION a_upd_release() RETURNS trigger AS $$
+BEGIN
+    UPDATE release_meta SET note = NEW.note WHERE id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+/* artwork; in its own schema */
+ALTER TABLE cover_art_archive.cover_art ADD COLUMN note TEXT;
+
+SET search_path = event_art_archive;
+CREATE INDEX event_art_idx_note ON event_art (note);
+
+COMMIT;
+"#;
+
+    #[test]
+    fn test_split_statements() {
+        let statements = split_statements(SCHEMA_CHANGE);
+        assert_eq!(statements.len(), 8);
+        assert_eq!(statements[0], "BEGIN");
+        assert_eq!(
+            statements[2],
+            "ALTER TABLE release ADD COLUMN note TEXT DEFAULT 'it''s; fine'"
+        );
+        assert!(statements[3].starts_with("CREATE OR REPLACE FUNCTION"));
+        assert!(statements[3].ends_with("$$ LANGUAGE 'plpgsql'"));
+        assert_eq!(
+            statements[4],
+            "ALTER TABLE cover_art_archive.cover_art ADD COLUMN note TEXT"
+        );
+        assert_eq!(statements[7], "COMMIT");
+    }
+
+    #[test]
+    fn test_kept_statements() {
+        let all = kept_statements(SCHEMA_CHANGE, |_| false);
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[0], "SET LOCAL search_path = musicbrainz, public");
+
+        let keep_only = ["musicbrainz"];
+        let kept = kept_statements(SCHEMA_CHANGE, |schema| !keep_only.contains(&schema));
+        assert_eq!(kept.len(), 4);
+        assert!(
+            kept[..3]
+                .iter()
+                .all(|statement| !statement.contains("art_archive."))
+        );
+        assert_eq!(kept[3], "SET LOCAL search_path = event_art_archive");
+        assert_eq!(
+            set_local("set local search_path to musicbrainz"),
+            "set local search_path to musicbrainz"
+        );
+    }
+
+    #[test]
+    fn test_mirror_scripts() -> MbLightResult<()> {
+        let dir = tempfile::tempdir()?;
+        for name in [
+            "29.all.sql",
+            "30.slave.sql",
+            "30.all.sql",
+            "30.master_only.sql",
+        ] {
+            fs::write(dir.path().join(name), "")?;
+        }
+
+        let scripts = mirror_scripts(dir.path(), SchemaSequence(30))?;
+        assert_eq!(
+            scripts,
+            vec![
+                dir.path().join("30.all.sql"),
+                dir.path().join("30.slave.sql")
+            ]
+        );
+
+        fs::write(dir.path().join("30.mirror.sql"), "")?;
+        assert_eq!(
+            mirror_scripts(dir.path(), SchemaSequence(30))?[1],
+            dir.path().join("30.mirror.sql")
+        );
+        assert!(matches!(
+            mirror_scripts(dir.path(), SchemaSequence(31)),
+            Err(MbLightError::MissingSqlScripts(_))
+        ));
+        Ok(())
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_migrate_schema`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_migrate_schema() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let checkout = tempfile::tempdir()?;
+        let dir = checkout.path().join("admin/sql").join(SCHEMA_CHANGE_DIR);
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("9001.all.sql"),
+            r#"\set ON_ERROR_STOP 1
+BEGIN;
+SET search_path = musicbrainz, public;
+CREATE TABLE fixture_migration (id INTEGER PRIMARY KEY);
+CREATE TABLE cover_art_archive.fixture_migration (id INTEGER PRIMARY KEY);
+SET search_path = cover_art_archive;
+COMMIT;"#,
+        )?;
+        fs::write(
+            dir.join("9001.mirror.sql"),
+            "SET search_path = musicbrainz; INSERT INTO fixture_migration VALUES (1); SET search_path = event_art_archive;",
+        )?;
+        fs::write(
+            dir.join("9002.all.sql"),
+            "DROP TABLE fixture_migration; SELECT * FROM missing_table;",
+        )?;
+
+        let mut settings = Settings::default();
+        settings.github.checkout = Some(checkout.path().to_path_buf());
+        settings.schema.keep_only = vec!["musicbrainz".to_string()];
+        let mb_light = MbLight::try_new(settings, db_url).await?;
+        sqlx::raw_sql(
+            r#"CREATE SCHEMA IF NOT EXISTS musicbrainz;
+               DROP TABLE IF EXISTS musicbrainz.fixture_migration;"#,
+        )
+        .execute(&mb_light.db)
+        .await?;
+        let previous = ReplicationControl::get(&mb_light.db).await?;
+
+        mb_light.migrate_schema(SchemaSequence(9001)).await?;
+        let rows: i64 = sqlx::query_scalar("SELECT count(*) FROM musicbrainz.fixture_migration")
+            .fetch_one(&mb_light.db)
+            .await?;
+        assert_eq!(rows, 1);
+        let skipped: bool =
+            sqlx::query_scalar("SELECT to_regclass('cover_art_archive.fixture_migration') IS NULL")
+                .fetch_one(&mb_light.db)
+                .await?;
+        assert!(skipped);
+        let control = ReplicationControl::get(&mb_light.db).await?;
+        assert_eq!(control.current_schema_sequence, Some(SchemaSequence(9001)));
+        // The scripts' search_path does not outlive the migration, on any pooled connection
+        let mut connections = Vec::new();
+        for _ in 0..mb_light.db.size() {
+            connections.push(mb_light.db.acquire().await?);
+        }
+        for connection in &mut connections {
+            let search_path: String = sqlx::query_scalar("SHOW search_path")
+                .fetch_one(&mut **connection)
+                .await?;
+            assert!(!search_path.contains("art_archive"), "{search_path}");
+        }
+        drop(connections);
+
+        // A failing statement rolls the whole schema change back
+        assert!(mb_light.migrate_schema(SchemaSequence(9002)).await.is_err());
+        let control = ReplicationControl::get(&mb_light.db).await?;
+        assert_eq!(control.current_schema_sequence, Some(SchemaSequence(9001)));
+        let kept: bool =
+            sqlx::query_scalar("SELECT to_regclass('musicbrainz.fixture_migration') IS NOT NULL")
+                .fetch_one(&mb_light.db)
+                .await?;
+        assert!(kept);
+
+        sqlx::raw_sql("DROP TABLE musicbrainz.fixture_migration")
+            .execute(&mb_light.db)
+            .await?;
+        if let Some(schema_sequence) = previous.current_schema_sequence {
+            ReplicationControl::set_schema_sequence(&mb_light.db, schema_sequence).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, postgres::PgArguments, query::Query};
+
+use crate::{MbLightError, error::MbLightResult};
+
+/// Column name to Postgres cast type for a single table.
+#[derive(Debug, Default, Clone)]
+pub struct TableColumns {
+    types: HashMap<String, String>,
+}
+
+impl TableColumns {
+    pub async fn fetch(db: &PgPool, schema: &str, table: &str) -> Result<Self, sqlx::Error> {
+        let rows: Vec<(String, String, String, String)> = sqlx::query_as(
+            r#"SELECT column_name::text, data_type::text, udt_schema::text, udt_name::text
+                 FROM information_schema.columns
+                WHERE table_schema = $1 AND table_name = $2"#,
+        )
+        .bind(schema)
+        .bind(table)
+        .fetch_all(db)
+        .await?;
+
+        let types = rows
+            .into_iter()
+            .map(|(column, data_type, udt_schema, udt_name)| {
+                let cast = match data_type.as_str() {
+                    // Array udt names are the element type prefixed with '_', in the schema
+                    // of the element type
+                    "ARRAY" => format!(
+                        "{}.{}[]",
+                        quote_ident(&udt_schema),
+                        quote_ident(udt_name.strip_prefix('_').unwrap_or(&udt_name))
+                    ),
+                    "USER-DEFINED" => {
+                        format!("{}.{}", quote_ident(&udt_schema), quote_ident(&udt_name))
+                    }
+                    _ => udt_name,
+                };
+                (column, cast)
+            })
+            .collect();
+
+        Ok(Self { types })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    pub fn cast(&self, column: &str) -> Option<&str> {
+        self.types.get(column).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+impl<const N: usize> From<[(&str, &str); N]> for TableColumns {
+    fn from(columns: [(&str, &str); N]) -> Self {
+        Self {
+            types: columns
+                .into_iter()
+                .map(|(c, t)| (c.to_string(), t.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// Lazily populated [`TableColumns`] lookup, keyed by `schema.table`.
+#[derive(Debug, Default)]
+pub struct ColumnCache {
+    tables: HashMap<String, TableColumns>,
+}
+
+impl ColumnCache {
+    pub async fn get(&mut self, db: &PgPool, fulltable: &str) -> MbLightResult<&TableColumns> {
+        if !self.tables.contains_key(fulltable) {
+            let (schema, table) = fulltable
+                .split_once('.')
+                .ok_or(MbLightError::MalformedPendingData("tablename"))?;
+            let columns = TableColumns::fetch(db, schema, table).await?;
+            if columns.is_empty() {
+                return Err(MbLightError::UnknownTable(fulltable.to_string()));
+            }
+            self.tables.insert(fulltable.to_string(), columns);
+        }
+
+        Ok(&self.tables[fulltable])
+    }
+}
+
+/// A SQL statement with its text-encoded bound parameters.
+///
+/// Every parameter is sent as text and explicitly cast to the column type in the
+/// statement, letting Postgres parse the value with the column type input function.
+#[derive(Debug, Default, PartialEq)]
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<Option<String>>,
+}
+
+impl Statement {
+    /// Adds a parameter for `column` and returns its placeholder.
+    pub fn push_param(
+        &mut self,
+        columns: &TableColumns,
+        column: &str,
+        value: &Value,
+    ) -> MbLightResult<String> {
+        let cast = columns
+            .cast(column)
+            .ok_or_else(|| MbLightError::UnknownColumn(column.to_string()))?;
+        self.params.push(to_pg_text(value, cast));
+        Ok(format!("${}::{}", self.params.len(), cast))
+    }
+
+    /// Renders the statement with its parameters inlined as quoted literals.
+    pub fn to_inline(&self) -> String {
+        let mut inline = String::with_capacity(self.sql.len());
+        let mut chars = self.sql.chars().peekable();
+        let mut in_ident = false;
+
+        while let Some(c) = chars.next() {
+            if c == '"' {
+                in_ident = !in_ident;
+            }
+
+            if c != '$' || in_ident {
+                inline.push(c);
+                continue;
+            }
+
+            let mut index = 0;
+            while let Some(digit) = chars.peek().and_then(|d| d.to_digit(10)) {
+                index = index * 10 + digit as usize;
+                chars.next();
+            }
+
+            match self.params.get(index.wrapping_sub(1)) {
+                Some(Some(value)) => inline.push_str(&format!("'{}'", value.replace('\'', "''"))),
+                Some(None) => inline.push_str("NULL"),
+                None => inline.push_str(&format!("${index}")),
+            }
+        }
+
+        inline
+    }
+
+    pub fn query(&self) -> Query<'_, Postgres, PgArguments> {
+        self.params
+            .iter()
+            .fold(sqlx::query(&self.sql), |query, param| query.bind(param))
+    }
+}
+
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Converts a `dbmirror2` json value to the Postgres text representation of `cast`.
+fn to_pg_text(value: &Value, cast: &str) -> Option<String> {
+    match value {
+        Value::Null => None,
+        // json columns are embedded as-is in the row json
+        _ if cast == "json" || cast == "jsonb" => Some(value.to_string()),
+        Value::String(s) => Some(s.clone()),
+        Value::Array(_) => Some(pg_array_literal(value)),
+        _ => Some(value.to_string()),
+    }
+}
+
+fn pg_array_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Array(values) => {
+            let elems: Vec<String> = values.iter().map(pg_array_literal).collect();
+            format!("{{{}}}", elems.join(","))
+        }
+        Value::String(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+        Value::Object(_) => {
+            let s = value.to_string();
+            format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+        Value::Number(_) | Value::Bool(_) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_array_literals() {
+        assert_eq!(to_pg_text(&json!([1, 2, 3]), "int4[]").unwrap(), "{1,2,3}");
+        assert_eq!(
+            to_pg_text(&json!(["a\"b", null, "c\\d"]), "text[]").unwrap(),
+            r#"{"a\"b",NULL,"c\\d"}"#
+        );
+    }
+
+    #[test]
+    fn test_inline_statement() {
+        let statement = Statement {
+            sql: r#"UPDATE "a"."b$1" SET "name" = $1::text, "c" = $2::int4 WHERE "id" = $3::int4"#
+                .to_string(),
+            params: vec![Some("O'Brien".to_string()), None, Some("12".to_string())],
+        };
+
+        assert_eq!(
+            statement.to_inline(),
+            r#"UPDATE "a"."b$1" SET "name" = 'O''Brien'::text, "c" = NULL::int4 WHERE "id" = '12'::int4"#
+        );
+    }
+
+    #[test]
+    fn test_json_columns_are_kept_as_json() {
+        assert_eq!(
+            to_pg_text(&json!(["x", {"a": 1}]), "jsonb").unwrap(),
+            r#"["x",{"a":1}]"#
+        );
+        assert_eq!(to_pg_text(&json!("x"), "json").unwrap(), r#""x""#);
+        assert_eq!(to_pg_text(&Value::Null, "jsonb"), None);
+    }
+
+    /// Run with `MBLIGHT_TEST_DB_URL=postgres://... cargo test -- --ignored test_fetch_columns`
+    #[tokio::test]
+    #[ignore]
+    async fn test_fetch_columns() -> MbLightResult<()> {
+        let db_url = std::env::var("MBLIGHT_TEST_DB_URL").expect("MBLIGHT_TEST_DB_URL");
+        let db = PgPool::connect(&db_url).await?;
+        sqlx::raw_sql(
+            r#"DROP SCHEMA IF EXISTS mblight_columns CASCADE;
+               CREATE SCHEMA mblight_columns;
+               CREATE TYPE mblight_columns.mood AS ENUM ('happy', 'sad');
+               CREATE TABLE mblight_columns.track (
+                   id INTEGER,
+                   mood mblight_columns.mood,
+                   moods mblight_columns.mood[],
+                   tags TEXT[]
+               );"#,
+        )
+        .execute(&db)
+        .await?;
+
+        let columns = TableColumns::fetch(&db, "mblight_columns", "track").await;
+        sqlx::query("DROP SCHEMA mblight_columns CASCADE")
+            .execute(&db)
+            .await?;
+        let columns = columns?;
+
+        assert_eq!(columns.cast("id"), Some("int4"));
+        assert_eq!(columns.cast("mood"), Some(r#""mblight_columns"."mood""#));
+        assert_eq!(columns.cast("moods"), Some(r#""mblight_columns"."mood"[]"#));
+        assert_eq!(columns.cast("tags"), Some(r#""pg_catalog"."text"[]"#));
+        Ok(())
+    }
+}
@@ -1,20 +1,42 @@
 use crate::MbLight;
 use crate::error::MbLightResult;
 use crate::settings::MbLightSettingsExt;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
+use std::{iter, pin::pin};
 
 use bytes::Bytes;
+use futures_util::{Stream, StreamExt, stream};
 use indicatif::ProgressBar;
 use sqlx::postgres::PgPoolCopyExt;
 use std::fs;
-use tar::Entry;
 use tracing::info;
 
 impl<S: MbLightSettingsExt> MbLight<S> {
+    /// COPY `reader` into `schema.table`, the reader is expected to report its
+    /// progress on `pb` (see [`ProgressBar::wrap_read`]).
     pub async fn pg_copy(
         &self,
-        mut entry: Entry<'_, impl Read>,
+        mut reader: impl Read,
+        schema: &str,
+        table: &str,
+        pb: ProgressBar,
+    ) -> MbLightResult<()> {
+        let mut buffer = vec![0u8; 8 * 1024 * 1024];
+        let chunks = stream::iter(iter::from_fn(move || match reader.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => Some(Ok(Bytes::copy_from_slice(&buffer[..n]))),
+            Err(e) => Some(Err(e)),
+        }));
+
+        self.pg_copy_chunks(chunks, schema, table, pb).await
+    }
+
+    /// COPY a stream of raw `COPY` text chunks into `schema.table`, the first error
+    /// aborts the COPY.
+    pub async fn pg_copy_chunks(
+        &self,
+        chunks: impl Stream<Item = io::Result<Bytes>>,
         schema: &str,
         table: &str,
         pb: ProgressBar,
@@ -23,31 +45,25 @@ impl<S: MbLightSettingsExt> MbLight<S> {
             .execute(&self.db)
             .await?;
 
-        let tx = self.db.begin().await?;
-
         let mut sink = self
             .db
             .copy_in_raw(&format!("COPY {}.{} FROM STDIN", schema, table))
             .await?;
 
-        let mut buffer = vec![0u8; 8 * 1024 * 1024];
-
-        loop {
-            let n = entry.read(&mut buffer)?;
-            if n == 0 {
-                break;
-            }
-
-            let chunk = Bytes::copy_from_slice(&buffer[..n]);
-            sink.send(chunk).await?;
-
-            pb.inc(n as u64);
+        let mut chunks = pin!(chunks);
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => sink.send(chunk).await?,
+                Err(e) => {
+                    // The read error is the one worth reporting
+                    let _ = sink.abort(e.to_string()).await;
+                    return Err(e.into());
+                }
+            };
         }
 
-        sink.finish().await?;
-
         pb.set_message(format!("Committing on {schema}.{table}"));
-        tx.commit().await?;
+        sink.finish().await?;
         sqlx::query(&format!("ALTER TABLE {}.{} SET LOGGED", schema, table))
             .execute(&self.db)
             .await?;
@@ -99,16 +115,6 @@ impl<S: MbLightSettingsExt> MbLight<S> {
             return Ok(true);
         }
 
-        let has_data: bool = self.has_data(schema, table).await?;
-
-        if has_data {
-            info!(
-                "Skipping {} (table {} already contains data)",
-                table, fulltable
-            );
-            return Ok(true);
-        }
-
         Ok(false)
     }
 }
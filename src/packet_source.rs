@@ -0,0 +1,339 @@
+//! Where replication packets are fetched from.
+//!
+//! By default [`MbLight`](crate::MbLight) downloads packets from the MetaBrainz
+//! replication endpoint with [`HttpPacketSource`], use
+//! [`MbLight::with_packet_source`](crate::MbLight::with_packet_source) to replace it.
+//! When a packet cache directory is configured, it is wrapped in a [`CachedPacketSource`].
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::future::BoxFuture;
+use tempfile::NamedTempFile;
+use tracing::info;
+
+use crate::{
+    MbLightError,
+    download::{musicbrainz::download_with_progress, retry::RetryPolicy},
+    error::MbLightResult,
+    verify::Verifier,
+};
+
+/// A source of `replication-{sequence}-v2.tar.bz2` packets.
+pub trait PacketSource: Send + Sync {
+    /// Makes the replication packet `sequence` available on the local filesystem.
+    ///
+    /// Must fail with [`MbLightError::NotFound`] when the packet does not exist yet.
+    fn fetch(&self, sequence: i32) -> BoxFuture<'_, MbLightResult<ReplicationPacket>>;
+
+    /// Called once the packet `sequence` and the ones before it are applied.
+    fn applied(&self, _sequence: i32) -> BoxFuture<'_, MbLightResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A replication packet archive on the local filesystem.
+#[derive(Debug)]
+pub struct ReplicationPacket {
+    path: PathBuf,
+    // Removed when the packet is dropped
+    _tempfile: Option<NamedTempFile>,
+}
+
+impl ReplicationPacket {
+    pub fn local(path: PathBuf) -> Self {
+        Self {
+            path,
+            _tempfile: None,
+        }
+    }
+
+    pub fn temporary(tempfile: NamedTempFile) -> Self {
+        Self {
+            path: tempfile.path().to_path_buf(),
+            _tempfile: Some(tempfile),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn packet_filename(sequence: i32) -> String {
+    format!("replication-{sequence}-v2.tar.bz2")
+}
+
+/// Downloads packets from the MetaBrainz replication endpoint into temporary files,
+/// verified against the checksums published next to them when there are any, and against
+/// their signature when the verifier has a public key.
+#[derive(Debug, Clone)]
+pub struct HttpPacketSource {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+    verifier: Verifier,
+    retry: RetryPolicy,
+}
+
+impl HttpPacketSource {
+    pub fn new(client: reqwest::Client, base_url: &str, token: &str) -> Self {
+        Self {
+            verifier: Verifier::new(client.clone()).with_required_checksums(false),
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Packets are not always listed in the checksum files, they are not required to be.
+    pub fn with_verifier(mut self, verifier: Verifier) -> Self {
+        self.verifier = verifier.with_required_checksums(false);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn packet_url(&self, sequence: i32) -> String {
+        self.file_url(&packet_filename(sequence))
+    }
+
+    fn file_url(&self, filename: &str) -> String {
+        format!("{}/{}?token={}", self.base_url, filename, self.token)
+    }
+}
+
+impl PacketSource for HttpPacketSource {
+    fn fetch(&self, sequence: i32) -> BoxFuture<'_, MbLightResult<ReplicationPacket>> {
+        Box::pin(async move {
+            let tmpfile = NamedTempFile::new()?;
+            download_with_progress(
+                &self.client,
+                &self.packet_url(sequence),
+                tmpfile.path(),
+                &self.retry,
+            )
+            .await?;
+            self.verifier
+                .verify(tmpfile.path(), &packet_filename(sequence), |filename| {
+                    self.file_url(filename)
+                })
+                .await?;
+            Ok(ReplicationPacket::temporary(tmpfile))
+        })
+    }
+}
+
+/// Reads packets from a local directory, such as a mounted volume in air-gapped networks.
+///
+/// Packets are used as they are, unless a verifier is set with
+/// [`DirectoryPacketSource::with_verifier`].
+#[derive(Debug, Clone)]
+pub struct DirectoryPacketSource {
+    dir: PathBuf,
+    verifier: Option<Verifier>,
+}
+
+impl DirectoryPacketSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            verifier: None,
+        }
+    }
+
+    /// Verifies packets against the checksum files and signatures stored next to them.
+    pub fn with_verifier(mut self, verifier: Verifier) -> Self {
+        self.verifier = Some(verifier.with_required_checksums(false));
+        self
+    }
+}
+
+impl PacketSource for DirectoryPacketSource {
+    fn fetch(&self, sequence: i32) -> BoxFuture<'_, MbLightResult<ReplicationPacket>> {
+        Box::pin(async move {
+            let path = self.dir.join(packet_filename(sequence));
+            if !tokio::fs::try_exists(&path).await? {
+                return Err(MbLightError::NotFound);
+            }
+
+            if let Some(verifier) = &self.verifier {
+                verifier
+                    .verify_local(&self.dir, &packet_filename(sequence))
+                    .await?;
+            }
+            Ok(ReplicationPacket::local(path))
+        })
+    }
+}
+
+/// Keeps the packets fetched from an inner source in a directory, keyed by sequence,
+/// and serves them from there on the next fetch.
+#[derive(Clone)]
+pub struct CachedPacketSource {
+    dir: PathBuf,
+    inner: Arc<dyn PacketSource>,
+    keep: Option<u32>,
+}
+
+impl CachedPacketSource {
+    pub fn new(dir: impl Into<PathBuf>, inner: Arc<dyn PacketSource>) -> Self {
+        Self {
+            dir: dir.into(),
+            inner,
+            keep: None,
+        }
+    }
+
+    /// Once a packet is applied, only keep the `keep` last applied ones. Every packet is
+    /// kept by default.
+    pub fn with_retention(mut self, keep: Option<u32>) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn cached_path(&self, sequence: i32) -> PathBuf {
+        self.dir.join(packet_filename(sequence))
+    }
+
+    /// Removes the cached packets up to `sequence`, returns how many were removed.
+    pub async fn remove_until(&self, sequence: i32) -> MbLightResult<usize> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let cached = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("replication-"))
+                .and_then(|name| name.strip_suffix("-v2.tar.bz2"))
+                .and_then(|sequence| sequence.parse::<i32>().ok());
+            if cached.is_some_and(|cached| cached <= sequence) {
+                tokio::fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl PacketSource for CachedPacketSource {
+    fn fetch(&self, sequence: i32) -> BoxFuture<'_, MbLightResult<ReplicationPacket>> {
+        Box::pin(async move {
+            let path = self.cached_path(sequence);
+            if tokio::fs::try_exists(&path).await? {
+                return Ok(ReplicationPacket::local(path));
+            }
+
+            let packet = self.inner.fetch(sequence).await?;
+            tokio::fs::create_dir_all(&self.dir).await?;
+            // Copy next to the final path first so concurrent readers never see a partial packet
+            let partial = NamedTempFile::new_in(&self.dir)?;
+            tokio::fs::copy(packet.path(), partial.path()).await?;
+            partial.persist(&path).map_err(|e| e.error)?;
+            Ok(ReplicationPacket::local(path))
+        })
+    }
+
+    fn applied(&self, sequence: i32) -> BoxFuture<'_, MbLightResult<()>> {
+        Box::pin(async move {
+            if let Some(keep) = self.keep {
+                let removed = self.remove_until(sequence - keep as i32).await?;
+                if removed > 0 {
+                    info!("Removed {removed} applied packets from the packet cache");
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_directory_source() -> MbLightResult<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("replication-42-v2.tar.bz2"), b"")?;
+        let source = DirectoryPacketSource::new(dir.path());
+
+        let packet = source.fetch(42).await?;
+        assert_eq!(packet.path(), dir.path().join("replication-42-v2.tar.bz2"));
+        assert!(matches!(
+            source.fetch(43).await,
+            Err(MbLightError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_source() -> MbLightResult<()> {
+        let upstream = tempfile::tempdir()?;
+        let cache = tempfile::tempdir()?;
+        std::fs::write(upstream.path().join("replication-42-v2.tar.bz2"), b"packet")?;
+        let source = CachedPacketSource::new(
+            cache.path(),
+            Arc::new(DirectoryPacketSource::new(upstream.path())),
+        );
+
+        let packet = source.fetch(42).await?;
+        assert_eq!(packet.path(), source.cached_path(42));
+
+        // Served from the cache once the upstream packet is gone
+        std::fs::remove_file(upstream.path().join("replication-42-v2.tar.bz2"))?;
+        let packet = source.fetch(42).await?;
+        assert_eq!(std::fs::read(packet.path())?, b"packet");
+        assert!(matches!(
+            source.fetch(43).await,
+            Err(MbLightError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_retention() -> MbLightResult<()> {
+        let upstream = tempfile::tempdir()?;
+        let cache = tempfile::tempdir()?;
+        for sequence in 40..=45 {
+            std::fs::write(upstream.path().join(packet_filename(sequence)), b"packet")?;
+        }
+        let inner: Arc<dyn PacketSource> = Arc::new(DirectoryPacketSource::new(upstream.path()));
+        let source = CachedPacketSource::new(cache.path(), inner.clone());
+        for sequence in 40..=45 {
+            source.fetch(sequence).await?;
+        }
+        std::fs::write(cache.path().join("notes.txt"), b"")?;
+
+        // Kept by default
+        source.applied(44).await?;
+        assert!(source.cached_path(40).exists());
+
+        let source = source.with_retention(Some(2));
+        source.applied(44).await?;
+        let mut cached: Vec<_> = std::fs::read_dir(cache.path())?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_, _>>()?;
+        cached.sort();
+        assert_eq!(
+            cached,
+            [
+                "notes.txt",
+                "replication-43-v2.tar.bz2",
+                "replication-44-v2.tar.bz2",
+                "replication-45-v2.tar.bz2",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,547 @@
+//! Multi-threaded bzip2 decoding.
+//!
+//! A bzip2 stream is a sequence of independently compressed blocks, each one starting with a
+//! 48-bit magic number which is not byte aligned. The input is scanned for block boundaries on
+//! a dedicated thread, every block is repacked into a standalone single-block stream decoded by
+//! a pool of worker threads with libbzip2, and the decoded blocks are read back in order.
+
+use std::{
+    io::{self, Read},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+
+use bzip2::read::BzDecoder;
+
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+const END_OF_STREAM_MAGIC: u64 = 0x1772_4538_5090;
+const MAGIC_MASK: u64 = (1 << 48) - 1;
+const READ_SIZE: usize = 1024 * 1024;
+/// A block magic number appearing by chance in compressed data splits a genuine block in
+/// candidates that fail to decode on their own, up to this many are decoded together.
+const MAX_MERGED_BLOCKS: usize = 8;
+
+/// Reader decoding a (possibly multi-stream) bzip2 input on `threads` worker threads.
+pub struct ParallelBzDecoder {
+    blocks: mpsc::Receiver<mpsc::Receiver<Decoded>>,
+    output: Vec<u8>,
+    pos: usize,
+}
+
+type Decoded = Result<Vec<u8>, DecodeError>;
+
+struct DecodeError {
+    error: io::Error,
+    /// The block that failed to decode, `None` when the input itself could not be split.
+    block: Option<Block>,
+}
+
+impl ParallelBzDecoder {
+    pub fn new<R: Read + Send + 'static>(input: R, threads: usize) -> Self {
+        let threads = threads.max(1);
+        // Bounds the number of blocks in memory, decoded or not
+        let (order_tx, order_rx) = mpsc::sync_channel(threads * 2);
+        let (jobs_tx, jobs_rx) = mpsc::channel::<(Block, mpsc::SyncSender<Decoded>)>();
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+
+        for _ in 0..threads {
+            let jobs = jobs_rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = jobs.lock().ok().and_then(|jobs| jobs.recv().ok());
+                    let Some((block, result)) = job else {
+                        break;
+                    };
+
+                    let decoded = match block.decode() {
+                        Ok(output) => Ok(output),
+                        Err(error) => Err(DecodeError {
+                            error,
+                            block: Some(block),
+                        }),
+                    };
+                    let _ = result.send(decoded);
+                }
+            });
+        }
+
+        thread::spawn(move || {
+            Splitter::new(input).run(|block| {
+                let (result_tx, result_rx) = mpsc::sync_channel(1);
+                // The reader was dropped
+                if order_tx.send(result_rx).is_err() {
+                    return false;
+                }
+
+                match block {
+                    Ok(block) => jobs_tx.send((block, result_tx)).is_ok(),
+                    Err(error) => {
+                        let _ = result_tx.send(Err(DecodeError { error, block: None }));
+                        false
+                    }
+                }
+            })
+        });
+
+        Self {
+            blocks: order_rx,
+            output: vec![],
+            pos: 0,
+        }
+    }
+
+    fn next_decoded(&mut self) -> Option<Decoded> {
+        let result = self.blocks.recv().ok()?;
+        Some(result.recv().unwrap_or_else(|_| {
+            Err(DecodeError {
+                error: io::Error::other("bzip2 decoder thread stopped"),
+                block: None,
+            })
+        }))
+    }
+
+    fn next_output(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(decoded) = self.next_decoded() else {
+            return Ok(None);
+        };
+
+        let DecodeError { error, block } = match decoded {
+            Ok(output) => return Ok(Some(output)),
+            Err(e) => e,
+        };
+
+        let Some(mut block) = block else {
+            return Err(error);
+        };
+
+        for _ in 1..MAX_MERGED_BLOCKS {
+            match self.next_decoded() {
+                Some(Err(DecodeError {
+                    block: Some(next), ..
+                })) => {
+                    block = block.merge(next);
+                    if let Ok(output) = block.decode() {
+                        return Ok(Some(output));
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        Err(error)
+    }
+}
+
+impl Read for ParallelBzDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            match self.next_output()? {
+                Some(output) => {
+                    self.output = output;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.output.len() - self.pos);
+        buf[..n].copy_from_slice(&self.output[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The bits of a compressed block, from its magic number up to the next one.
+struct Block {
+    /// Compression level of the stream, from its `BZh1` to `BZh9` header.
+    level: u8,
+    bits: BitWriter,
+}
+
+impl Block {
+    fn new(level: u8, src: &[u8], start: u64, end: u64) -> Self {
+        let mut bits = BitWriter::default();
+        bits.extend(src, start, end);
+        Self { level, bits }
+    }
+
+    fn merge(mut self, next: Block) -> Self {
+        self.bits.extend(&next.bits.bytes, 0, next.bits.len);
+        self
+    }
+
+    /// A single-block stream, whose combined CRC is the block CRC following the magic number.
+    fn to_stream(&self) -> Vec<u8> {
+        let crc = read_bits(&self.bits.bytes, 48, 32);
+        let mut stream = BitWriter::default();
+        for byte in [b'B', b'Z', b'h', self.level] {
+            stream.push_byte(byte);
+        }
+        stream.extend(&self.bits.bytes, 0, self.bits.len);
+        stream.push_bits(END_OF_STREAM_MAGIC, 48);
+        stream.push_bits(crc, 32);
+        stream.bytes
+    }
+
+    fn decode(&self) -> io::Result<Vec<u8>> {
+        let mut output = vec![];
+        BzDecoder::new(self.to_stream().as_slice()).read_to_end(&mut output)?;
+        Ok(output)
+    }
+}
+
+/// Finds the blocks of the bzip2 streams read from `input`.
+struct Splitter<R> {
+    input: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Splitter<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input,
+            buf: vec![],
+            eof: false,
+        }
+    }
+
+    /// Calls `emit` with every block, until it returns `false` or an error is emitted.
+    fn run(mut self, mut emit: impl FnMut(io::Result<Block>) -> bool) {
+        if let Err(e) = self.split(&mut emit) {
+            emit(Err(e));
+        }
+    }
+
+    fn split(&mut self, emit: &mut impl FnMut(io::Result<Block>) -> bool) -> io::Result<()> {
+        // Bit position in `buf`, streams start on a byte boundary
+        let mut pos: u64 = 0;
+        loop {
+            let start = (pos / 8) as usize;
+            if !self.fill(start + 1)? {
+                return Ok(());
+            }
+
+            let level = match self.fill(start + 4)? {
+                true if &self.buf[start..start + 3] == b"BZh"
+                    && (b'1'..=b'9').contains(&self.buf[start + 3]) =>
+                {
+                    self.buf[start + 3]
+                }
+                _ => return Err(invalid_data("invalid bzip2 stream header")),
+            };
+            pos += 32;
+
+            loop {
+                self.fill((pos + 48).div_ceil(8) as usize)?;
+                match read_bits(&self.buf, pos, 48) {
+                    END_OF_STREAM_MAGIC => {
+                        // Skip the stream CRC and padding
+                        pos = (pos + 80).div_ceil(8) * 8;
+                        break;
+                    }
+                    BLOCK_MAGIC => {
+                        let end = self.block_end(pos)?;
+                        if !emit(Ok(Block::new(level, &self.buf, pos, end))) {
+                            return Ok(());
+                        }
+
+                        let consumed = (end / 8) as usize;
+                        self.buf.drain(..consumed);
+                        pos = end - consumed as u64 * 8;
+                    }
+                    _ => return Err(invalid_data("invalid bzip2 block header")),
+                }
+            }
+        }
+    }
+
+    /// Bit position of the magic number following the block starting at `start`.
+    fn block_end(&mut self, start: u64) -> io::Result<u64> {
+        let mut from = start + 48;
+        loop {
+            match self.find_magic(from)? {
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated bzip2 stream",
+                    ));
+                }
+                Some((pos, BLOCK_MAGIC)) => return Ok(pos),
+                Some((pos, _)) => {
+                    // Only trust an end of stream followed by the end of input or another stream
+                    let next = (pos + 80).div_ceil(8) as usize;
+                    self.fill(next + 3)?;
+                    if self.buf.len() <= next || self.buf[next..].starts_with(b"BZh") {
+                        return Ok(pos);
+                    }
+                    from = pos + 1;
+                }
+            }
+        }
+    }
+
+    /// First block or end of stream magic number at or after bit `from`.
+    fn find_magic(&mut self, mut from: u64) -> io::Result<Option<(u64, u64)>> {
+        loop {
+            if let Some(found) = scan(&self.buf, from) {
+                return Ok(Some(found));
+            }
+
+            // Magic numbers starting in the last 6 bytes are not checked by `scan`
+            from = from.max((self.buf.len() as u64 * 8).saturating_sub(48));
+            if !self.fill(self.buf.len() + 1)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads until `buf` holds at least `len` bytes, returns `false` if the input ends first.
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        while self.buf.len() < len && !self.eof {
+            let filled = self.buf.len();
+            self.buf.resize(filled + READ_SIZE, 0);
+            match self.input.read(&mut self.buf[filled..]) {
+                Ok(n) => {
+                    self.buf.truncate(filled + n);
+                    self.eof = n == 0;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(filled),
+                Err(e) => {
+                    self.buf.truncate(filled);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(self.buf.len() >= len)
+    }
+}
+
+/// Scans `buf` for a magic number starting at or after bit `from`.
+fn scan(buf: &[u8], from: u64) -> Option<(u64, u64)> {
+    let first = (from / 8) as usize;
+    let mut window = 0u64;
+    for (i, byte) in buf.iter().enumerate().skip(first) {
+        window = (window << 8) | *byte as u64;
+        // The window holds 7 bytes, enough for a magic number starting at any bit of the first
+        if i < first + 6 {
+            continue;
+        }
+
+        let start = (i - 6) as u64 * 8;
+        for shift in 0..8 {
+            let magic = (window >> (8 - shift)) & MAGIC_MASK;
+            if (magic == BLOCK_MAGIC || magic == END_OF_STREAM_MAGIC) && start + shift >= from {
+                return Some((start + shift, magic));
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads `count` bits, most significant first, missing bits are read as 0.
+fn read_bits(src: &[u8], pos: u64, count: u32) -> u64 {
+    (pos..pos + count as u64).fold(0, |value, bit| {
+        let byte = src.get((bit / 8) as usize).copied().unwrap_or_default();
+        (value << 1) | ((byte >> (7 - bit % 8)) & 1) as u64
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Number of bits written.
+    len: u64,
+}
+
+impl BitWriter {
+    fn push_bit(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit && let Some(last) = self.bytes.last_mut() {
+            *last |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    fn push_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.push_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        let shift = self.len % 8;
+        match self.bytes.last_mut() {
+            Some(last) if shift != 0 => {
+                *last |= byte >> shift;
+                self.bytes.push(byte << (8 - shift));
+            }
+            _ => self.bytes.push(byte),
+        }
+        self.len += 8;
+    }
+
+    /// Appends the bits of `src` in `start..end`.
+    fn extend(&mut self, src: &[u8], start: u64, end: u64) {
+        let mut pos = start;
+        while pos < end && !pos.is_multiple_of(8) {
+            self.push_bits(read_bits(src, pos, 1), 1);
+            pos += 1;
+        }
+        while pos + 8 <= end {
+            self.push_byte(src[(pos / 8) as usize]);
+            pos += 8;
+        }
+        while pos < end {
+            self.push_bits(read_bits(src, pos, 1), 1);
+            pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use bzip2::{Compression, read::MultiBzDecoder, write::BzEncoder};
+
+    use super::*;
+
+    /// Text spanning several 100k blocks at compression level 1.
+    fn generate(seed: u64, len: usize) -> Vec<u8> {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let words = [
+            "artist",
+            "release",
+            "recording",
+            "\t",
+            "\\N",
+            "\n",
+            "42",
+            "mbid",
+        ];
+        let mut data = vec![];
+        while data.len() < len {
+            data.extend_from_slice(words[rng.usize(..words.len())].as_bytes());
+            data.push(rng.alphanumeric() as u8);
+        }
+        data
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn parallel_decode(compressed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut output = vec![];
+        ParallelBzDecoder::new(io::Cursor::new(compressed), 4).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_same_output_as_bzdecoder() -> io::Result<()> {
+        for data in [generate(1, 1_500_000), generate(2, 10), vec![]] {
+            let compressed = compress(&data);
+            let mut expected = vec![];
+            BzDecoder::new(compressed.as_slice()).read_to_end(&mut expected)?;
+
+            assert_eq!(parallel_decode(compressed)?, expected);
+            assert_eq!(expected, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_tar_archive() -> io::Result<()> {
+        let mut builder = tar::Builder::new(BzEncoder::new(vec![], Compression::fast()));
+        for (i, name) in ["mbdump/artist", "mbdump/release", "TIMESTAMP"]
+            .iter()
+            .enumerate()
+        {
+            let data = generate(i as u64, 400_000 * i + 10);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_slice())?;
+        }
+        let compressed = builder.into_inner()?.finish()?;
+
+        let mut expected = tar::Archive::new(BzDecoder::new(compressed.as_slice()));
+        let mut archive = tar::Archive::new(ParallelBzDecoder::new(
+            io::Cursor::new(compressed.clone()),
+            3,
+        ));
+        for (expected, entry) in expected.entries()?.zip(archive.entries()?) {
+            let (mut expected, mut entry) = (expected?, entry?);
+            assert_eq!(entry.path()?, expected.path()?);
+            let (mut expected_data, mut data) = (vec![], vec![]);
+            expected.read_to_end(&mut expected_data)?;
+            entry.read_to_end(&mut data)?;
+            assert_eq!(data, expected_data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_multistream() -> io::Result<()> {
+        let mut data = generate(3, 300_000);
+        let mut compressed = compress(&data);
+        let second = generate(4, 200_000);
+        compressed.extend(compress(&second));
+        data.extend(second);
+
+        let mut expected = vec![];
+        MultiBzDecoder::new(compressed.as_slice()).read_to_end(&mut expected)?;
+        assert_eq!(expected, data);
+        assert_eq!(parallel_decode(compressed.clone())?, expected);
+
+        // Both paths of the archive reader read every stream
+        for bzip2_threads in [1, 4] {
+            let mut decoded = vec![];
+            crate::tar_helper::read_archive(io::Cursor::new(compressed.clone()), bzip2_threads)
+                .into_inner()
+                .read_to_end(&mut decoded)?;
+            assert_eq!(decoded, expected, "{bzip2_threads} bzip2 threads");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_split_block() -> io::Result<()> {
+        let data = generate(5, 50_000);
+        let compressed = compress(&data);
+        let mut blocks = vec![];
+        Splitter::new(compressed.as_slice()).run(|block| {
+            blocks.push(block);
+            true
+        });
+        let block = blocks.remove(0)?;
+        assert!(blocks.is_empty());
+
+        // As if a magic number was found in the middle of the block
+        let middle = block.bits.len / 2;
+        let first = Block::new(block.level, &block.bits.bytes, 0, middle);
+        let second = Block::new(block.level, &block.bits.bytes, middle, block.bits.len);
+        assert!(first.decode().is_err());
+        assert!(second.decode().is_err());
+        assert_eq!(first.merge(second).decode()?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let mut compressed = compress(&generate(6, 300_000));
+        compressed.truncate(compressed.len() / 2);
+        assert!(parallel_decode(compressed).is_err());
+    }
+}
@@ -1,9 +1,16 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use config::{Config, Environment, File};
 use serde::Deserialize;
+use tempfile::env::temp_dir;
 
-use crate::error::MbLightResult;
+use crate::{
+    download::{musicbrainz::MUSICBRAINZ_FTP, retry::RetryPolicy},
+    error::MbLightResult,
+};
 
 pub trait MbLightSettingsExt {
     fn db_user(&self) -> &str;
@@ -17,6 +24,113 @@ pub trait MbLightSettingsExt {
     fn musicbrainz_token(&self) -> &str;
     fn should_skip_table(&self, table: &str) -> bool;
     fn should_skip_schema(&self, schema: &str) -> bool;
+
+    /// Directory where replication packets are kept once downloaded, `None` disables caching.
+    fn packet_cache_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Applied packets kept in the packet cache, counting back from the last applied one.
+    /// `None` keeps them all, for caches shared by several mirrors.
+    fn packet_cache_keep(&self) -> Option<u32> {
+        None
+    }
+
+    /// Maximum number of concurrent replication packet downloads.
+    fn fetch_concurrency(&self) -> usize {
+        DEFAULT_FETCH_CONCURRENCY
+    }
+
+    /// Whether downloads are checked against the published `SHA256SUMS`/`MD5SUMS`.
+    fn verify_checksums(&self) -> bool {
+        true
+    }
+
+    /// Armored public key downloads detached signatures are verified with, `None` skips it.
+    fn gpg_public_key(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Base URL of the full export, a MusicBrainz mirror or a caching proxy.
+    fn fullexport_url(&self) -> &str {
+        MUSICBRAINZ_FTP
+    }
+
+    /// Tag, branch or commit of `musicbrainz-server` the SQL scripts are fetched from, `None`
+    /// resolves the `production` commit deployed when the dump was exported.
+    fn sql_ref(&self) -> Option<&str> {
+        None
+    }
+
+    /// Directory where the SQL scripts are kept, per schema sequence.
+    fn sql_cache_dir(&self) -> PathBuf {
+        temp_dir().join("mbpg-light").join("sql")
+    }
+
+    /// Token authenticating GitHub requests, which lifts the anonymous API rate limit.
+    fn github_token(&self) -> Option<&str> {
+        None
+    }
+
+    /// Local clone of `musicbrainz-server` whose `admin/sql` scripts are used as is,
+    /// instead of fetching them from GitHub.
+    fn musicbrainz_server_checkout(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Full export imported by `init`, like `20261014-001803`, `None` picks the latest one.
+    fn dump_version(&self) -> Option<&str> {
+        None
+    }
+
+    /// How failed downloads of dumps and replication packets are retried.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Maximum number of dump tables copied concurrently during `init`, up to 512 MiB of a
+    /// table copied slower than it is decoded are spooled to a temporary file.
+    fn ingest_concurrency(&self) -> usize {
+        DEFAULT_INGEST_CONCURRENCY
+    }
+
+    /// Threads decompressing dump archives and replication packets, `1` uses the
+    /// single-threaded decoder.
+    fn bzip2_threads(&self) -> usize {
+        1
+    }
+
+    /// Stream dump archives from the HTTP response into the database during `init`,
+    /// instead of downloading them to a temporary file first.
+    fn stream_dumps(&self) -> bool {
+        false
+    }
+
+    /// Publish the changes applied by `sync` as JSON lines, one per replicated transaction.
+    fn cdc_output(&self) -> Option<CdcOutput> {
+        None
+    }
+
+    /// Channel notified with the sequence, timestamp and per-table change counts of each
+    /// applied packet.
+    fn notify_channel(&self) -> Option<&str> {
+        None
+    }
+
+    /// Channel notified with the tables of each replicated transaction, when it commits.
+    fn notify_xid_channel(&self) -> Option<&str> {
+        None
+    }
+
+    /// SQL scripts and materialized views `sync` runs once packets are applied.
+    fn post_sync(&self) -> Option<&PostSyncSettings> {
+        None
+    }
+
+    /// Address the `http` feature serves `/healthz`, `/status` and `/metrics` on during `sync`.
+    fn http_listen(&self) -> Option<&str> {
+        None
+    }
 }
 
 impl MbLightSettingsExt for Settings {
@@ -65,6 +179,96 @@ impl MbLightSettingsExt for Settings {
         let keep = self.schema_keep_only();
         !keep.is_empty() && !keep.iter().any(|s| s == schema)
     }
+
+    fn packet_cache_dir(&self) -> Option<&Path> {
+        self.replication.packet_cache.as_deref()
+    }
+
+    fn packet_cache_keep(&self) -> Option<u32> {
+        self.replication.packet_cache_keep
+    }
+
+    fn fetch_concurrency(&self) -> usize {
+        self.replication.fetch_concurrency
+    }
+
+    fn verify_checksums(&self) -> bool {
+        !self.musicbrainz.skip_checksums
+    }
+
+    fn gpg_public_key(&self) -> Option<&Path> {
+        self.musicbrainz.gpg_public_key.as_deref()
+    }
+
+    fn fullexport_url(&self) -> &str {
+        self.musicbrainz
+            .fullexport_url
+            .as_deref()
+            .unwrap_or(MUSICBRAINZ_FTP)
+    }
+
+    fn sql_ref(&self) -> Option<&str> {
+        self.musicbrainz.sql_ref.as_deref()
+    }
+
+    fn sql_cache_dir(&self) -> PathBuf {
+        match &self.musicbrainz.sql_cache {
+            Some(dir) => dir.clone(),
+            None => temp_dir().join("mbpg-light").join("sql"),
+        }
+    }
+
+    fn github_token(&self) -> Option<&str> {
+        self.github.token.as_deref()
+    }
+
+    fn musicbrainz_server_checkout(&self) -> Option<&Path> {
+        self.github.checkout.as_deref()
+    }
+
+    fn dump_version(&self) -> Option<&str> {
+        self.musicbrainz.dump_version.as_deref()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.download.max_retries,
+            initial_backoff: Duration::from_millis(self.download.initial_backoff_ms),
+            max_backoff: Duration::from_millis(self.download.max_backoff_ms),
+        }
+    }
+
+    fn ingest_concurrency(&self) -> usize {
+        self.import.concurrency
+    }
+
+    fn bzip2_threads(&self) -> usize {
+        self.import.bzip2_threads
+    }
+
+    fn stream_dumps(&self) -> bool {
+        self.import.streaming
+    }
+
+    fn cdc_output(&self) -> Option<CdcOutput> {
+        self.cdc.output.as_deref().map(CdcOutput::from)
+    }
+
+    fn notify_channel(&self) -> Option<&str> {
+        self.notify.channel.as_deref()
+    }
+
+    fn notify_xid_channel(&self) -> Option<&str> {
+        self.notify.xid_channel.as_deref()
+    }
+
+    fn post_sync(&self) -> Option<&PostSyncSettings> {
+        Some(&self.post_sync).filter(|post_sync| !post_sync.is_empty())
+    }
+
+    fn http_listen(&self) -> Option<&str> {
+        self.http.listen.as_deref()
+    }
 }
 
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -73,6 +277,22 @@ pub struct Settings {
     pub musicbrainz: MusicbrainzSettings,
     pub tables: TableSettings,
     pub schema: SchemaSettings,
+    #[serde(default)]
+    pub replication: ReplicationSettings,
+    #[serde(default)]
+    pub download: DownloadSettings,
+    #[serde(default)]
+    pub import: ImportSettings,
+    #[serde(default)]
+    pub github: GithubSettings,
+    #[serde(default)]
+    pub cdc: CdcSettings,
+    #[serde(default)]
+    pub notify: NotifySettings,
+    #[serde(default)]
+    pub post_sync: PostSyncSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
 }
 
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -88,6 +308,13 @@ pub struct DBSettings {
 pub struct MusicbrainzSettings {
     pub url: String,
     pub token: String,
+    #[serde(default)]
+    pub skip_checksums: bool,
+    pub gpg_public_key: Option<PathBuf>,
+    pub fullexport_url: Option<String>,
+    pub dump_version: Option<String>,
+    pub sql_ref: Option<String>,
+    pub sql_cache: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -97,7 +324,144 @@ pub struct TableSettings {
 
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct SchemaSettings {
-    keep_only: Vec<String>,
+    pub(crate) keep_only: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReplicationSettings {
+    pub packet_cache: Option<PathBuf>,
+    pub packet_cache_keep: Option<u32>,
+    #[serde(default = "default_fetch_concurrency")]
+    pub fetch_concurrency: usize,
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        Self {
+            packet_cache: None,
+            packet_cache_keep: None,
+            fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DownloadSettings {
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        Self {
+            max_retries: policy.max_retries,
+            initial_backoff_ms: policy.initial_backoff.as_millis() as u64,
+            max_backoff_ms: policy.max_backoff.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct GithubSettings {
+    pub token: Option<String>,
+    pub checkout: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct CdcSettings {
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct NotifySettings {
+    pub channel: Option<String>,
+    pub xid_channel: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct HttpSettings {
+    pub listen: Option<String>,
+}
+
+/// Work run by `sync` after replication, each step independently of the others: SQL files in
+/// order, then the materialized views.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct PostSyncSettings {
+    pub after: PostSyncTrigger,
+    pub sql_files: Vec<PathBuf>,
+    /// Materialized views refreshed with `REFRESH MATERIALIZED VIEW`.
+    pub refresh: Vec<String>,
+    /// Materialized views refreshed with `REFRESH MATERIALIZED VIEW CONCURRENTLY`, which
+    /// needs a unique index on the view but does not block its readers.
+    pub refresh_concurrently: Vec<String>,
+}
+
+impl PostSyncSettings {
+    pub fn is_empty(&self) -> bool {
+        self.sql_files.is_empty() && self.refresh.is_empty() && self.refresh_concurrently.is_empty()
+    }
+}
+
+/// When the post-sync steps run.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostSyncTrigger {
+    /// After each applied packet.
+    Packet,
+    /// Once the latest published packet is applied, if any packet was.
+    #[default]
+    CaughtUp,
+}
+
+/// Where the changes applied by `sync` are published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CdcOutput {
+    Stdout,
+    /// A file the changes are appended to.
+    File(PathBuf),
+    /// A listening Unix socket, connected to for each replication packet.
+    UnixSocket(PathBuf),
+}
+
+impl From<&str> for CdcOutput {
+    fn from(output: &str) -> Self {
+        match output {
+            "stdout" | "-" => CdcOutput::Stdout,
+            _ => match output.strip_prefix("unix:") {
+                Some(path) => CdcOutput::UnixSocket(PathBuf::from(path)),
+                None => CdcOutput::File(PathBuf::from(output)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ImportSettings {
+    pub concurrency: usize,
+    pub bzip2_threads: usize,
+    pub streaming: bool,
+}
+
+impl Default for ImportSettings {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_INGEST_CONCURRENCY,
+            bzip2_threads: 1,
+            streaming: false,
+        }
+    }
+}
+
+const DEFAULT_FETCH_CONCURRENCY: usize = 4;
+const DEFAULT_INGEST_CONCURRENCY: usize = 4;
+
+fn default_fetch_concurrency() -> usize {
+    DEFAULT_FETCH_CONCURRENCY
 }
 
 impl Settings {
@@ -1,17 +1,44 @@
-use bzip2::bufread::BzDecoder;
+use bzip2::bufread::MultiBzDecoder;
 use std::{
     fs::File,
-    io::{BufReader, Read},
+    io::{self, BufReader, Read},
     path::Path,
 };
 use tar::Archive;
 
-use crate::error::MbLightResult;
+use crate::{error::MbLightResult, parallel_bzip2::ParallelBzDecoder};
 
-pub fn get_archive(tmpfile: &Path) -> MbLightResult<Archive<impl Read>> {
+/// Opens a `.tar.bz2` archive, decompressed on `bzip2_threads` threads when more than one.
+/// Archives made of several concatenated bzip2 streams are read whole either way.
+pub fn get_archive(tmpfile: &Path, bzip2_threads: usize) -> MbLightResult<Archive<impl Read>> {
     let f = File::open(tmpfile)?;
-    let reader = BufReader::new(f);
-    let decompressor = BzDecoder::new(reader);
-    let archive = Archive::new(decompressor);
-    Ok(archive)
+    Ok(read_archive(f, bzip2_threads))
+}
+
+/// Reads a `.tar.bz2` archive from `reader`, see [`get_archive`].
+pub fn read_archive<R: Read + Send + 'static>(
+    reader: R,
+    bzip2_threads: usize,
+) -> Archive<impl Read> {
+    let reader = BufReader::new(reader);
+    let decompressor = if bzip2_threads > 1 {
+        Decompressor::Parallel(ParallelBzDecoder::new(reader, bzip2_threads))
+    } else {
+        Decompressor::Single(MultiBzDecoder::new(reader))
+    };
+    Archive::new(decompressor)
+}
+
+enum Decompressor<R> {
+    Single(MultiBzDecoder<BufReader<R>>),
+    Parallel(ParallelBzDecoder),
+}
+
+impl<R: Read> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decompressor::Single(decoder) => decoder.read(buf),
+            Decompressor::Parallel(decoder) => decoder.read(buf),
+        }
+    }
 }
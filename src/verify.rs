@@ -0,0 +1,479 @@
+//! Integrity checks of downloaded dumps and replication packets.
+//!
+//! MusicBrainz publishes `SHA256SUMS` and `MD5SUMS` files next to the archives, and a
+//! detached `<archive>.asc` GPG signature for each of them.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use md5::Md5;
+use reqwest::StatusCode;
+use sha2::{Sha256, digest::DynDigest};
+use tracing::{info, warn};
+
+use crate::{MbLightError, error::MbLightResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the published checksum file.
+    pub fn sums_filename(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "SHA256SUMS",
+            ChecksumAlgorithm::Md5 => "MD5SUMS",
+        }
+    }
+
+    /// Hex encoded digest of the file at `path`.
+    pub fn digest_file(&self, path: &Path) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = self.hasher();
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+
+        Ok(to_hex(&hasher.finalize()))
+    }
+
+    /// Incremental hasher, for data that is not stored in a file.
+    pub fn hasher(&self) -> Box<dyn DynDigest + Send> {
+        match self {
+            ChecksumAlgorithm::Sha256 => Box::new(Sha256::default()),
+            ChecksumAlgorithm::Md5 => Box::new(Md5::default()),
+        }
+    }
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Compares the computed `got` digest of `filename` to the `expected` one.
+pub fn ensure_digest(filename: &str, expected: &str, got: String) -> MbLightResult<()> {
+    if got != expected {
+        return Err(MbLightError::ChecksumMissmatch {
+            file: filename.to_string(),
+            expected: expected.to_string(),
+            got,
+        });
+    }
+
+    Ok(())
+}
+
+/// Content of a `SHA256SUMS` or `MD5SUMS` file.
+#[derive(Debug, Clone)]
+pub struct Checksums {
+    algorithm: ChecksumAlgorithm,
+    sums: HashMap<String, String>,
+}
+
+impl Checksums {
+    /// Parses `<hex digest>  <filename>` lines, binary mode `*<filename>` included.
+    pub fn parse(algorithm: ChecksumAlgorithm, content: &str) -> Self {
+        let sums = content
+            .lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .map(|(digest, filename)| {
+                let filename = filename.trim_start().trim_start_matches('*');
+                (filename.to_string(), digest.to_ascii_lowercase())
+            })
+            .collect();
+
+        Self { algorithm, sums }
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&str> {
+        self.sums.get(filename).map(String::as_str)
+    }
+
+    /// Checks the file at `path` against the published digest of `filename`.
+    ///
+    /// Returns `false` when `filename` is not listed.
+    pub fn verify(&self, path: &Path, filename: &str) -> MbLightResult<bool> {
+        let Some(expected) = self.get(filename) else {
+            return Ok(false);
+        };
+
+        let got = self.algorithm.digest_file(path)?;
+        ensure_digest(filename, expected, got)?;
+        Ok(true)
+    }
+}
+
+/// Verifies downloaded archives against the checksums and signatures published next to them.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    client: reqwest::Client,
+    checksums: bool,
+    required_checksums: bool,
+    public_key: Option<PathBuf>,
+    // Set once no checksum file was found for an archive whose checksum is optional
+    unpublished: Arc<AtomicBool>,
+}
+
+impl Verifier {
+    /// Checksum verification only.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            checksums: true,
+            required_checksums: true,
+            public_key: None,
+            unpublished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn with_checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Whether an archive no checksum file lists is rejected, the default for full exports.
+    /// Otherwise it is only logged, and once no checksum file is published at all, the
+    /// following archives are not looked up anymore.
+    pub fn with_required_checksums(mut self, required: bool) -> Self {
+        self.required_checksums = required;
+        self
+    }
+
+    /// Also verify the detached `<archive>.asc` signature against this armored public key.
+    pub fn with_public_key(mut self, public_key: impl Into<PathBuf>) -> Self {
+        self.public_key = Some(public_key.into());
+        self
+    }
+
+    /// Verifies the downloaded `filename` stored at `path`, `sibling_url` maps a file
+    /// name to its URL in the same remote directory.
+    pub async fn verify(
+        &self,
+        path: &Path,
+        filename: &str,
+        sibling_url: impl Fn(&str) -> String,
+    ) -> MbLightResult<()> {
+        self.verify_checksum(path, filename, &sibling_url).await?;
+
+        if let Some(public_key) = &self.public_key {
+            let signature_url = sibling_url(&format!("{filename}.asc"));
+            let Some(signature) = self.get_text(&signature_url).await? else {
+                return Err(MbLightError::InvalidSignature {
+                    file: filename.to_string(),
+                    reason: "no detached signature published".to_string(),
+                });
+            };
+
+            let public_key = public_key.clone();
+            let path = path.to_path_buf();
+            let filename = filename.to_string();
+            tokio::task::spawn_blocking(move || {
+                verify_signature(&public_key, &signature, &path, &filename)
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`Verifier::verify`] for an archive stored in `dir`, next to its checksum and
+    /// signature files.
+    pub async fn verify_local(&self, dir: &Path, filename: &str) -> MbLightResult<()> {
+        let verifier = self.clone();
+        let (dir, filename) = (dir.to_path_buf(), filename.to_string());
+        tokio::task::spawn_blocking(move || verifier.verify_local_blocking(&dir, &filename))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn verify_local_blocking(&self, dir: &Path, filename: &str) -> MbLightResult<()> {
+        let path = dir.join(filename);
+        if self.checksums {
+            let mut verified = false;
+            for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Md5] {
+                let sums = dir.join(algorithm.sums_filename());
+                if !sums.exists() {
+                    continue;
+                }
+
+                let checksums = Checksums::parse(algorithm, &std::fs::read_to_string(sums)?);
+                if checksums.verify(&path, filename)? {
+                    info!("{filename} {} checksum verified", algorithm.sums_filename());
+                    verified = true;
+                    break;
+                }
+            }
+
+            if !verified {
+                self.missing_checksum(filename)?;
+            }
+        }
+
+        if let Some(public_key) = &self.public_key {
+            let Ok(signature) = std::fs::read_to_string(dir.join(format!("{filename}.asc"))) else {
+                return Err(MbLightError::InvalidSignature {
+                    file: filename.to_string(),
+                    reason: "no detached signature found".to_string(),
+                });
+            };
+            verify_signature(public_key, &signature, &path, filename)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether [`Verifier::verify`] checks signatures, which needs the whole archive
+    /// before it can be trusted.
+    pub fn verifies_signatures(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    /// Published digest of `filename`, from the first checksum file listing it.
+    ///
+    /// Returns `None` when checksum verification is disabled, and when no checksum file lists
+    /// `filename` unless checksums are required, see [`Verifier::with_required_checksums`].
+    pub async fn published_checksum(
+        &self,
+        filename: &str,
+        sibling_url: &impl Fn(&str) -> String,
+    ) -> MbLightResult<Option<(ChecksumAlgorithm, String)>> {
+        if !self.checksums || (!self.required_checksums && self.unpublished.load(Ordering::Relaxed))
+        {
+            return Ok(None);
+        }
+
+        let mut published = false;
+        for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Md5] {
+            let Some(content) = self
+                .get_text(&sibling_url(algorithm.sums_filename()))
+                .await?
+            else {
+                continue;
+            };
+
+            published = true;
+            if let Some(expected) = Checksums::parse(algorithm, &content).get(filename) {
+                return Ok(Some((algorithm, expected.to_string())));
+            }
+        }
+
+        if !published && !self.required_checksums {
+            warn!(
+                "No checksum file is published next to {filename}, archives there are not checked"
+            );
+            self.unpublished.store(true, Ordering::Relaxed);
+            return Ok(None);
+        }
+        self.missing_checksum(filename)?;
+        Ok(None)
+    }
+
+    /// Fails for an unlisted `filename` when checksums are required, warns otherwise.
+    fn missing_checksum(&self, filename: &str) -> MbLightResult<()> {
+        if self.required_checksums {
+            return Err(MbLightError::MissingChecksum(filename.to_string()));
+        }
+        warn!("No published checksum for {filename}, it is not verified");
+        Ok(())
+    }
+
+    async fn verify_checksum(
+        &self,
+        path: &Path,
+        filename: &str,
+        sibling_url: &impl Fn(&str) -> String,
+    ) -> MbLightResult<()> {
+        let Some((algorithm, expected)) = self.published_checksum(filename, sibling_url).await?
+        else {
+            return Ok(());
+        };
+
+        let path = path.to_path_buf();
+        let got = tokio::task::spawn_blocking(move || algorithm.digest_file(&path))
+            .await
+            .map_err(io::Error::other)??;
+        ensure_digest(filename, &expected, got)?;
+        info!("{filename} {} checksum verified", algorithm.sums_filename());
+        Ok(())
+    }
+
+    async fn get_text(&self, url: &str) -> MbLightResult<Option<String>> {
+        let response = self.client.get(url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.text().await?))
+    }
+}
+
+/// Verifies a detached armored signature with `gpg`, in a throwaway keyring holding only
+/// `public_key`.
+fn verify_signature(
+    public_key: &Path,
+    signature: &str,
+    path: &Path,
+    filename: &str,
+) -> MbLightResult<()> {
+    let home = tempfile::tempdir()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // gpg warns about homedirs readable by others
+        std::fs::set_permissions(home.path(), std::fs::Permissions::from_mode(0o700))?;
+    }
+    let signature_path = home.path().join(format!("{filename}.asc"));
+    std::fs::write(&signature_path, signature)?;
+
+    let gpg = |args: &[&std::ffi::OsStr]| -> MbLightResult<()> {
+        let output = Command::new("gpg")
+            .arg("--batch")
+            .arg("--homedir")
+            .arg(home.path())
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            return Err(MbLightError::InvalidSignature {
+                file: filename.to_string(),
+                reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    };
+
+    gpg(&["--import".as_ref(), public_key.as_os_str()])?;
+    gpg(&[
+        "--verify".as_ref(),
+        signature_path.as_os_str(),
+        path.as_os_str(),
+    ])?;
+    info!("{filename} signature verified");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate, matchers::path};
+
+    use super::*;
+
+    #[test]
+    fn test_verify_checksums() -> MbLightResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mbdump.tar.bz2");
+        std::fs::write(&path, b"hello")?;
+
+        let checksums = Checksums::parse(
+            ChecksumAlgorithm::Sha256,
+            "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824 *mbdump.tar.bz2\n\
+             0000000000000000000000000000000000000000000000000000000000000000  mbdump-derived.tar.bz2\n",
+        );
+
+        assert!(checksums.verify(&path, "mbdump.tar.bz2")?);
+        assert!(!checksums.verify(&path, "mbdump-stats.tar.bz2")?);
+        assert!(matches!(
+            checksums.verify(&path, "mbdump-derived.tar.bz2"),
+            Err(MbLightError::ChecksumMissmatch { .. })
+        ));
+
+        let md5 = Checksums::parse(
+            ChecksumAlgorithm::Md5,
+            "5d41402abc4b2a76b9719d911017c592  mbdump.tar.bz2",
+        );
+        assert!(md5.verify(&path, "mbdump.tar.bz2")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_checksum_is_rejected() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        Mock::given(path("/SHA256SUMS"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("0000  mbdump-derived.tar.bz2\n"),
+            )
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir()?;
+        let archive = dir.path().join("mbdump.tar.bz2");
+        std::fs::write(&archive, b"hello")?;
+        let sibling_url = |filename: &str| format!("{}/{filename}", server.uri());
+
+        let verifier = Verifier::new(reqwest::Client::new());
+        assert!(matches!(
+            verifier.verify(&archive, "mbdump.tar.bz2", sibling_url).await,
+            Err(MbLightError::MissingChecksum(file)) if file == "mbdump.tar.bz2"
+        ));
+        assert!(matches!(
+            verifier.verify_local(dir.path(), "mbdump.tar.bz2").await,
+            Err(MbLightError::MissingChecksum(_))
+        ));
+
+        // Only skip_checksums opts out
+        let verifier = verifier.with_checksums(false);
+        verifier
+            .verify(&archive, "mbdump.tar.bz2", sibling_url)
+            .await?;
+        verifier.verify_local(dir.path(), "mbdump.tar.bz2").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_optional_checksums() -> MbLightResult<()> {
+        let server = MockServer::start().await;
+        Mock::given(path("/SHA256SUMS"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/MD5SUMS"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir()?;
+        let packet = dir.path().join("replication-101-v2.tar.bz2");
+        std::fs::write(&packet, b"hello")?;
+        let sibling_url = |filename: &str| format!("{}/{filename}", server.uri());
+
+        let verifier = Verifier::new(reqwest::Client::new()).with_required_checksums(false);
+        // Checksum files are only looked up until they are found missing
+        for _ in 0..3 {
+            verifier
+                .verify(&packet, "replication-101-v2.tar.bz2", sibling_url)
+                .await?;
+        }
+        verifier
+            .verify_local(dir.path(), "replication-101-v2.tar.bz2")
+            .await?;
+
+        // A listed packet is still checked
+        std::fs::write(
+            dir.path().join("MD5SUMS"),
+            "00000000000000000000000000000000  replication-101-v2.tar.bz2\n",
+        )?;
+        assert!(matches!(
+            verifier
+                .verify_local(dir.path(), "replication-101-v2.tar.bz2")
+                .await,
+            Err(MbLightError::ChecksumMissmatch { .. })
+        ));
+        Ok(())
+    }
+}